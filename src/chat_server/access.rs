@@ -0,0 +1,161 @@
+use std::net::IpAddr;
+
+/// A range of addresses in CIDR notation, like `10.0.0.0/8` or `fd00::/8`. A bare address is a
+/// range holding just that address.
+#[derive(Clone, Debug)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u32
+}
+
+impl IpRange {
+    pub fn parse(range: &str) -> Result<IpRange, String> {
+        let mut split = range.splitn(2, '/');
+        let network: IpAddr = match split.next().unwrap_or("").parse() {
+            Ok(network) => network,
+            Err(_) => {
+                return Err(format!("`{}` is not a valid address range", range));
+            }
+        };
+
+        let max_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+
+        let prefix_len = match split.next() {
+            Some(prefix_len) => {
+                match prefix_len.parse() {
+                    Ok(prefix_len) if prefix_len <= max_len => prefix_len,
+                    _ => {
+                        return Err(format!("`{}` is not a valid address range, the prefix length must be between 0 and {}", range, max_len));
+                    }
+                }
+            },
+            None => max_len
+        };
+
+        Ok(IpRange {
+            network: network,
+            prefix_len: prefix_len
+        })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, unmap_ipv4(address)) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => prefix_matches(&network.octets(), &address.octets(), self.prefix_len),
+            (IpAddr::V6(network), IpAddr::V6(address)) => prefix_matches(&network.octets(), &address.octets(), self.prefix_len),
+            _ => false
+        }
+    }
+}
+
+/// Which addresses may connect, checked when a connection is accepted.
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    /// If there are any, only addresses in one of these ranges may connect
+    pub allow: Vec<IpRange>,
+
+    /// Addresses in any of these ranges may never connect, even if they are also allowed
+    pub deny: Vec<IpRange>
+}
+
+impl AccessList {
+    pub fn permits(&self, address: IpAddr) -> bool {
+        if self.deny.iter().any(|range| range.contains(address)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|range| range.contains(address))
+    }
+}
+
+/// A server listening on an IPv6 socket sees IPv4 clients as `::ffff:a.b.c.d`, treat those as the IPv4
+/// address so IPv4 ranges still match them.
+fn unmap_ipv4(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            if segments[..5].iter().all(|s| *s == 0) && segments[5] == 0xffff {
+                IpAddr::V4(::std::net::Ipv4Addr::new(
+                    (segments[6] >> 8) as u8, segments[6] as u8,
+                    (segments[7] >> 8) as u8, segments[7] as u8
+                ))
+            } else {
+                address
+            }
+        },
+        IpAddr::V4(_) => address
+    }
+}
+
+/// Are the first `prefix_len` bits of the two addresses the same
+fn prefix_matches(network: &[u8], address: &[u8], prefix_len: u32) -> bool {
+    let full_bytes = (prefix_len / 8) as usize;
+    if network[..full_bytes] != address[..full_bytes] {
+        return false;
+    }
+
+    let rest = prefix_len % 8;
+    if rest == 0 {
+        return true;
+    }
+
+    let mask = 0xffu8 << (8 - rest);
+    network[full_bytes] & mask == address[full_bytes] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use super::IpRange;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn bare_address_holds_only_itself() {
+        let range = IpRange::parse("10.1.2.3").unwrap();
+        assert!(range.contains(ip("10.1.2.3")));
+        assert!(!range.contains(ip("10.1.2.4")));
+    }
+
+    #[test]
+    fn ipv4_prefixes() {
+        let range = IpRange::parse("10.0.0.0/8").unwrap();
+        assert!(range.contains(ip("10.255.0.1")));
+        assert!(!range.contains(ip("11.0.0.1")));
+
+        // A prefix that doesn't end on a byte boundary
+        let range = IpRange::parse("192.168.4.0/22").unwrap();
+        assert!(range.contains(ip("192.168.7.255")));
+        assert!(!range.contains(ip("192.168.8.0")));
+
+        assert!(IpRange::parse("0.0.0.0/0").unwrap().contains(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn ipv6_prefixes() {
+        let range = IpRange::parse("fd00::/8").unwrap();
+        assert!(range.contains(ip("fd12:3456::1")));
+        assert!(!range.contains(ip("fe80::1")));
+        assert!(!range.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn mapped_ipv4_addresses_match_ipv4_ranges() {
+        let range = IpRange::parse("10.0.0.0/8").unwrap();
+        assert!(range.contains(ip("::ffff:10.0.0.1")));
+        assert!(!range.contains(ip("::ffff:11.0.0.1")));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        assert!(IpRange::parse("10.0.0.0/33").is_err());
+        assert!(IpRange::parse("fd00::/129").is_err());
+        assert!(IpRange::parse("10.0.0.0/x").is_err());
+        assert!(IpRange::parse("alice").is_err());
+        assert!(IpRange::parse("").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crypto::scrypt::{scrypt_simple, scrypt_check, ScryptParams};
+
+use super::config::LogLevel;
+use super::user::{username_key, Username};
+
+/// Usernames that have been claimed with a password, persisted to disk.
+///
+/// The file holds one account per line, the username followed by a space and the scrypt hash of its
+/// password. Usernames can't contain whitespace so no escaping is needed.
+pub struct AccountStore {
+    /// Where accounts are read from at startup and appended to when created
+    path: PathBuf,
+
+    /// Hashmap of usernames => password hashes
+    accounts: HashMap<Username, String>,
+
+    /// Hashmap of lookup keys => usernames, see `username_key`
+    keys: HashMap<String, Username>
+}
+
+impl AccountStore {
+    /// Load the account store from the given file. A missing file is treated as an empty store and
+    /// will be created when the first account is registered.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<AccountStore> {
+        let mut store = AccountStore {
+            path: path.as_ref().to_path_buf(),
+            accounts: HashMap::new(),
+            keys: HashMap::new()
+        };
+
+        let file = match File::open(&store.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(store);
+            },
+            Err(e) => {
+                return Err(e);
+            }
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            let mut split = line.split_whitespace();
+            match (split.next(), split.next()) {
+                (Some(user_name), Some(hash)) => {
+                    // Accounts created before names were compared by key may clash, the first one keeps the key
+                    let key = username_key(user_name);
+                    if store.keys.contains_key(&key) {
+                        super::log_at(LogLevel::Warn, format!("Account {} looks the same as {}, it can only be logged in to by its exact name", user_name, store.keys[&key]));
+                    } else {
+                        store.keys.insert(key, user_name.to_string());
+                    }
+                    store.accounts.insert(user_name.to_string(), hash.to_string());
+                },
+                _ => {
+                    super::log_at(LogLevel::Warn, format!("Skipping malformed line in account store: {}", line));
+                }
+            }
+        }
+
+        Ok(store)
+    }
+
+    /// Is there an account for the username, or for a name that looks the same
+    pub fn contains(&self, user_name: &Username) -> bool {
+        self.accounts.contains_key(user_name) || self.keys.contains_key(&username_key(user_name))
+    }
+
+    /// The name the account that looks like the given username was registered under, so "ALICE" can log in as "alice".
+    pub fn canonical_name(&self, user_name: &Username) -> Option<Username> {
+        if self.accounts.contains_key(user_name) {
+            return Some(user_name.clone());
+        }
+        self.keys.get(&username_key(user_name)).cloned()
+    }
+
+    /// Returns the hash of the account's password, if there is an account with exactly this name.
+    pub fn hash_of(&self, user_name: &Username) -> Option<String> {
+        self.accounts.get(user_name).cloned()
+    }
+
+    /// Save a new account for the username, with a hash made by `hash_password`.
+    pub fn create(&mut self, user_name: &Username, hash: String) -> Result<(), String> {
+        if self.contains(user_name) {
+            return Err("That username is already registered to an account".into());
+        }
+
+        let saved = OpenOptions::new().append(true).create(true).open(&self.path).and_then(|mut file| {
+            writeln!(file, "{} {}", user_name, hash)
+        });
+        if let Err(e) = saved {
+            super::log_at(LogLevel::Error, format!("Failed to save account store {:?}, {:?}", self.path, e));
+            return Err("Failed to save the account, please try again later".into());
+        }
+
+        self.accounts.insert(user_name.clone(), hash);
+        self.keys.insert(username_key(user_name), user_name.clone());
+        Ok(())
+    }
+}
+
+/// Hash a password to store in an account. This is slow on purpose, so it is only run on the
+/// hashing thread, see `PasswordHasher`.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    scrypt_simple(password, &ScryptParams::new(14, 8, 1)).map_err(|e| format!("Failed to hash password, {:?}", e))
+}
+
+/// Returns true if the password matches a hash made by `hash_password`. Just as slow, see above.
+pub fn check_password(password: &str, hash: &str) -> bool {
+    scrypt_check(password, hash).unwrap_or(false)
+}
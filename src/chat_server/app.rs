@@ -1,8 +1,20 @@
 use std::collections::HashMap;
+use std::collections::vec_deque::VecDeque;
+use std::net::IpAddr;
+use std::rc::Rc;
 use mio::Token;
+use rand::{thread_rng, Rng};
+use time;
 
-use super::user::{ChatUser, Username};
-use super::room::{ChatRoom, Roomname};
+use super::accounts::AccountStore;
+use super::bans::{Ban, BanMask, BanStore};
+use super::command::ModeChange;
+use super::config::{Limits, LogLevel, ServerConfig};
+use super::history::{HistoryEntry, HistoryStore};
+use super::user::{username_key, ChatUser, Presence, SuspendedUser, Username, UsernamePolicy};
+use super::roles::{Permission, RoomRole, ServerRole};
+use super::room::{ChatRoom, Roomname, Topic};
+use super::room_store::RoomStore;
 
 pub struct ChatApp {
 	/// Hashmap of connections with a registered username
@@ -11,33 +23,112 @@ pub struct ChatApp {
     /// Hashmap of rooms currently available
     rooms: HashMap<Roomname, ChatRoom>,
 
-    /// Hashmap of usernames => tokens for quick lookup and to prevent different connections
-    /// from claiming the same username
-    user_name_lookup: HashMap<Username, Token>
+    /// Hashmap of username lookup keys => tokens for quick lookup and to prevent different connections
+    /// from claiming the same username, or ones that only look different. See `username_key`
+    user_name_lookup: HashMap<String, Token>,
+
+    /// What new usernames have to look like
+    name_policy: UsernamePolicy,
+
+    /// Lookup keys of the accounts that are server operators once logged in
+    operators: Vec<String>,
+
+    /// Usernames that have been claimed with a password and can only be used after a /login
+    accounts: AccountStore,
+
+    /// Hashmap of resume tokens => users whose connection dropped and who may still /resume
+    suspended_users: HashMap<String, SuspendedUser>,
+
+    /// Every message broadcast to each room
+    history: HistoryStore,
+
+    /// Who is banned from which rooms
+    bans: BanStore,
+
+    /// Where persistent rooms and their topics are saved
+    room_store: RoomStore,
+
+    /// Hashmap of connections => the address they connect from, used to check bans
+    addresses: HashMap<Token, IpAddr>,
+
+    /// The room users are put in when they first pick a username
+    default_room: Roomname,
+
+    /// Rooms from the `initial` setting, which are always persistent like the default room
+    initial_rooms: Vec<Roomname>,
+
+    limits: Limits
 }
 
 impl<'a> ChatApp {
 
-	pub fn new() -> ChatApp {
+	pub fn new(config: &ServerConfig, accounts: AccountStore, history: HistoryStore, bans: BanStore, room_store: RoomStore) -> ChatApp {
 		let mut app = ChatApp {
 			users: HashMap::new(),
 			rooms: HashMap::new(),
-			user_name_lookup: HashMap::new()
+			user_name_lookup: HashMap::new(),
+			name_policy: UsernamePolicy::new(config),
+			operators: config.operators.iter().map(|name| username_key(name)).collect(),
+			accounts: accounts,
+			suspended_users: HashMap::new(),
+			history: history,
+			bans: bans,
+			room_store: room_store,
+			addresses: HashMap::new(),
+			default_room: config.default_room.clone(),
+			initial_rooms: config.initial_rooms.clone(),
+			limits: config.limits
 		};
 
-		app.rooms.insert("default".to_string(), ChatRoom::new("default".to_string()));
+		app.ensure_room(&config.default_room, true);
+		for room_name in config.initial_rooms.iter() {
+			app.ensure_room(room_name, true);
+		}
+
+		// Rooms made persistent with /persist, and the topics, modes and roles of every persistent room
+		let saved_rooms = app.room_store.loaded().to_vec();
+		for saved in saved_rooms {
+			app.ensure_room(&saved.name, true);
+			let accounts = &app.accounts;
+			let room = app.rooms.get_mut(&saved.name).unwrap();
+			room.persistent = true;
+			room.topic = saved.topic;
+			room.invite_only = saved.invite_only;
+			room.key = saved.key;
+			room.hidden = saved.hidden;
+			room.read_only = saved.read_only;
+
+			// Names without an account lose their roles when they leave, see `forget_roles`
+			room.roles = saved.roles.into_iter()
+				.filter(|&(ref name, _)| accounts.contains(name))
+				.collect();
+		}
+
+		// Rooms that weren't persistent are gone after a restart, and so is what was said in them
+		let gone: Vec<Roomname> = app.history.room_names().into_iter()
+			.filter(|room_name| !app.rooms.contains_key(room_name))
+			.collect();
+		app.history.forget(&gone);
 
 		app
 	}
 
-	/// If the given token were to send a message, return the list tokens for connections that should recieve that message.
-	pub fn get_message_recipients(&self, sender: Token) -> Vec<Token> {
-		let room_name = &self.users.get(&sender).unwrap().location;
-		return self.rooms.get(room_name).unwrap().members.iter().cloned().collect();
+	/// Returns the tokens of every connection in the room.
+	pub fn get_room_tokens(&self, room_name: &Roomname) -> Vec<Token> {
+		match self.rooms.get(room_name) {
+			Some(room) => room.members.iter().cloned().collect(),
+			None => Vec::new()
+		}
 	}
 
-	pub fn get_room_list(&self) -> Vec<Roomname> {
-		self.rooms.keys().cloned().collect()
+	/// Returns every room the user can see, in no particular order.
+	pub fn get_rooms(&self, token: Token) -> Vec<&ChatRoom> {
+		self.rooms.values().filter(|room| self.can_see_room(token, room)).collect()
+	}
+
+	/// Hidden rooms can only be seen by the users in them and by operators.
+	pub fn can_see_room(&self, token: Token, room: &ChatRoom) -> bool {
+		!room.hidden || self.is_operator(token) || room.members.contains(&token)
 	}
 
 	pub fn get_username(&self, token: Token) -> Option<Username> {
@@ -51,53 +142,1100 @@ impl<'a> ChatApp {
 		};
 	}
 
-	pub fn move_rooms(&mut self, token: Token, dest: &Roomname) {
+	/// Returns the name of the room the user has focused, see `ChatUser::location`.
+	pub fn get_location(&self, token: Token) -> Option<Roomname> {
+		self.users.get(&token).map(|user| user.location.clone())
+	}
+
+	/// Returns every room the user is in, in the order they joined them.
+	pub fn get_joined_rooms(&self, token: Token) -> Vec<Roomname> {
+		self.users.get(&token).map(|user| user.rooms.clone()).unwrap_or(Vec::new())
+	}
+
+	/// Returns the users in the given room sorted by username, or None if there is no such room or
+	/// it is hidden from the user asking.
+	pub fn get_room_members(&self, token: Token, room_name: &Roomname) -> Option<Vec<&ChatUser>> {
+		let room = match self.rooms.get(room_name) {
+			Some(room) if self.can_see_room(token, room) => room,
+			_ => {
+				return None;
+			}
+		};
+
+		let mut members: Vec<&ChatUser> = room.members.iter().filter_map(|token| self.users.get(token)).collect();
+		members.sort_by(|a, b| a.user_name.cmp(&b.user_name));
+		return Some(members);
+	}
+
+	/// Returns the topic of the room, if the room exists and has one.
+	pub fn get_topic(&self, room_name: &Roomname) -> Option<Topic> {
+		self.rooms.get(room_name).and_then(|room| room.topic.clone())
+	}
+
+	/// Set the topic of the user's current room, returning the room's name.
+	pub fn set_topic(&mut self, token: Token, text: String) -> Result<Roomname, String> {
+		let (user_name, room_name) = match self.users.get(&token) {
+			Some(user) => (user.user_name.clone(), user.location.clone()),
+			None => {
+				return Err("Select a username before setting a topic".into());
+			}
+		};
+
+		let persistent = {
+			let room = self.rooms.get_mut(&room_name).unwrap();
+			room.set_topic(text, user_name);
+			room.persistent
+		};
+
+		if persistent {
+			try!(self.room_store.save(self.rooms.values()));
+		}
+		Ok(room_name)
+	}
+
+	/// Returns the user's role in the room, member if they haven't been given one.
+	pub fn get_room_role(&self, token: Token, room_name: &Roomname) -> RoomRole {
+		match (self.users.get(&token), self.rooms.get(room_name)) {
+			(Some(user), Some(room)) => room.role_of(&username_key(&user.user_name)),
+			_ => RoomRole::Member
+		}
+	}
+
+	pub fn is_operator(&self, token: Token) -> bool {
+		self.users.get(&token).map(|user| user.role == ServerRole::Operator).unwrap_or(false)
+	}
+
+	/// Checked before every command runs. Operators have every permission.
+	pub fn check_permission(&self, token: Token, permission: Permission) -> Result<(), String> {
+		if permission == Permission::Anyone {
+			return Ok(());
+		}
+
+		let user = match self.users.get(&token) {
+			Some(user) => user,
+			None => {
+				return Err("Select a username first".into());
+			}
+		};
+
+		if user.role == ServerRole::Operator {
+			return Ok(());
+		}
+
+		match permission {
+			Permission::Anyone | Permission::User => Ok(()),
+			Permission::Room(role) => {
+				if self.get_room_role(token, &user.location) >= role {
+					Ok(())
+				} else {
+					Err(format!("You need to be {} or above in room {} to do that", role.name(), user.location))
+				}
+			},
+			Permission::Operator => Err("Only server operators can do that".into())
+		}
+	}
+
+	/// Give a user a role in the current room of whoever is giving it, returning the room's name. You
+	/// can only hand out roles below your own, to users whose role is below your own.
+	pub fn set_room_role(&mut self, token: Token, target_name: &Username, role: RoomRole) -> Result<Roomname, String> {
+		let room_name = match self.get_location(token) {
+			Some(room_name) => room_name,
+			None => {
+				return Err("Select a username first".into());
+			}
+		};
+
+		let target_name = match self.get_token(target_name).and_then(|target| self.get_username(target)) {
+			Some(target_name) => target_name,
+			None => {
+				return Err(format!("There is no user named {}", target_name));
+			}
+		};
+
+		let target_key = username_key(&target_name);
+		let persistent = {
+			let room = self.rooms.get_mut(&room_name).unwrap();
+			if self.users[&token].role != ServerRole::Operator {
+				let own_role = room.role_of(&username_key(&self.users[&token].user_name));
+				if role >= own_role || room.role_of(&target_key) >= own_role {
+					return Err("You can only give roles below your own, to users whose role is below your own".into());
+				}
+			}
+
+			room.set_role(target_key, role);
+			room.persistent
+		};
+
+		if persistent {
+			try!(self.room_store.save(self.rooms.values()));
+		}
+		Ok(room_name)
+	}
+
+	/// Change a mode of the user's current room, returning the room's name. The default room is where
+	/// everyone starts out, so it can't be made private.
+	pub fn set_mode(&mut self, token: Token, change: ModeChange) -> Result<Roomname, String> {
+		let room_name = try!(self.get_location(token).ok_or("Select a username first".to_string()));
+		let persistent = {
+			let room = self.rooms.get_mut(&room_name).unwrap();
+			match change {
+				ModeChange::InviteOnly(true) | ModeChange::Key(Some(_)) | ModeChange::Hidden(true) if room_name == self.default_room => {
+					return Err(format!("Room {} is the default room, it can't be made private", room_name));
+				},
+				ModeChange::InviteOnly(invite_only) => {
+					room.invite_only = invite_only;
+				},
+				ModeChange::Key(key) => {
+					room.key = key;
+				},
+				ModeChange::Hidden(hidden) => {
+					room.hidden = hidden;
+				},
+				ModeChange::ReadOnly(read_only) => {
+					room.read_only = read_only;
+				}
+			}
+			room.persistent
+		};
+
+		if persistent {
+			try!(self.room_store.save(self.rooms.values()));
+		}
+		Ok(room_name)
+	}
+
+	/// Returns the modes of the room, see `ChatRoom::modes`.
+	pub fn get_modes(&self, room_name: &Roomname) -> Option<String> {
+		self.rooms.get(room_name).map(|room| room.modes())
+	}
+
+	/// Let a user into the current room of whoever is inviting them, even if it is invite only or
+	/// needs a key. Returns the room's name and the invited user's token so they can be told.
+	pub fn invite(&mut self, token: Token, target_name: &Username) -> Result<(Roomname, Token), String> {
+		let room_name = try!(self.get_location(token).ok_or("Select a username first".to_string()));
+		let target = try!(self.get_token(target_name).ok_or(format!("There is no user named {}", target_name)));
+		if self.users[&target].rooms.contains(&room_name) {
+			return Err(format!("{} is already in room {}", target_name, room_name));
+		}
+
+		let target_key = username_key(&self.users[&target].user_name);
+		self.rooms.get_mut(&room_name).unwrap().invites.insert(target_key);
+		Ok((room_name, target))
+	}
+
+	/// Make the room persistent so it stays when everyone leaves and across restarts, or let it be
+	/// cleaned up again.
+	pub fn set_persistent(&mut self, room_name: &Roomname, persistent: bool) -> Result<(), String> {
+		// Users are put in the default room when they arrive, so it must always exist
+		if !persistent && (*room_name == self.default_room || self.initial_rooms.contains(room_name)) {
+			return Err(format!("Room {} is set up in the server's config, it is always persistent", room_name));
+		}
+
+		match self.rooms.get_mut(room_name) {
+			Some(room) => {
+				room.persistent = persistent;
+			},
+			None => {
+				return Err(format!("There is no room named {}", room_name));
+			}
+		}
+		self.room_store.save(self.rooms.values())
+	}
+
+	/// Remember where a new connection comes from, so bans on addresses can be checked. Tokens are
+	/// reused, so a connection without an address clears whatever the last one had.
+	pub fn set_address(&mut self, token: Token, address: Option<IpAddr>) {
+		match address {
+			Some(address) => {
+				self.addresses.insert(token, address);
+			},
+			None => {
+				self.addresses.remove(&token);
+			}
+		}
+	}
+
+	/// Kick a user out of the current room of whoever is kicking them, returning the room's name and
+	/// the user's token. Leaving the room is up to the caller.
+	pub fn kick(&mut self, token: Token, target_name: &Username) -> Result<(Roomname, Token), String> {
+		let room_name = try!(self.get_location(token).ok_or("Select a username first".to_string()));
+		let target = match self.get_token(target_name) {
+			Some(target) if self.users[&target].rooms.contains(&room_name) => target,
+			_ => {
+				return Err(format!("There is no user named {} in room {}", target_name, room_name));
+			}
+		};
+
+		// Operators would pass the check below, and kicking themselves out of the default room when it is
+		// their only room would disconnect them
+		if target == token {
+			return Err("You can't kick yourself, use /part to leave the room".into());
+		}
+
+		try!(self.check_can_moderate(token, target, &room_name));
+		Ok((room_name, target))
+	}
+
+	/// Ban a username, username pattern or address from the current room of whoever is banning, for
+	/// `duration_secs` or for good. Returns the room's name and the members who are now banned from it,
+	/// leaving the room is up to the caller.
+	pub fn ban(&mut self, token: Token, mask: &str, duration_secs: Option<u64>) -> Result<(Roomname, Vec<Token>), String> {
+		let (set_by, room_name) = match self.users.get(&token) {
+			Some(user) => (user.user_name.clone(), user.location.clone()),
+			None => {
+				return Err("Select a username first".into());
+			}
+		};
+
+		let target = BanMask::parse(mask);
+
+		// Banning someone who is online by name bans where they connect from as well
+		let mut address = None;
+		if let BanMask::Name(_) = target {
+			if let Some(target_token) = self.get_token(&mask.to_string()) {
+				try!(self.check_can_moderate(token, target_token, &room_name));
+				address = self.addresses.get(&target_token).cloned();
+			}
+		}
+
+		try!(self.bans.add(Ban {
+			room: room_name.clone(),
+			mask: mask.to_string(),
+			target: target,
+			address: address,
+			expires: duration_secs.map(|secs| time::get_time() + time::Duration::seconds(secs as i64)),
+			set_by: set_by
+		}));
+
+		let banned = self.get_room_tokens(&room_name).into_iter()
+			.filter(|&member| {
+				let user = &self.users[&member];
+				self.check_ban(&user.user_name, user.role == ServerRole::Operator, self.addresses.get(&member).cloned(), &room_name).is_err()
+			})
+			.collect();
+		Ok((room_name, banned))
+	}
+
+	/// Lift a ban from the current room of whoever is lifting it, returning the room's name. The mask
+	/// has to be given the same way it was banned.
+	pub fn unban(&mut self, token: Token, mask: &str) -> Result<Roomname, String> {
+		let room_name = try!(self.get_location(token).ok_or("Select a username first".to_string()));
+		if try!(self.bans.remove(&room_name, mask)) {
+			Ok(room_name)
+		} else {
+			Err(format!("{} isn't banned from room {}", mask, room_name))
+		}
+	}
+
+	/// Stop a user's messages from reaching the current room of whoever is muting them, for
+	/// `duration_secs` or until they are unmuted. Returns the room's name and the user's name.
+	pub fn mute(&mut self, token: Token, target_name: &Username, duration_secs: Option<u64>) -> Result<(Roomname, Username), String> {
+		let room_name = try!(self.get_location(token).ok_or("Select a username first".to_string()));
+		let target = try!(self.get_token(target_name).ok_or(format!("There is no user named {}", target_name)));
+		try!(self.check_can_moderate(token, target, &room_name));
+
+		let until = duration_secs.map(|secs| time::get_time() + time::Duration::seconds(secs as i64));
+		let target_name = self.users[&target].user_name.clone();
+		self.rooms.get_mut(&room_name).unwrap().mutes.insert(username_key(&target_name), until);
+		Ok((room_name, target_name))
+	}
+
+	/// Lift a mute in the current room of whoever is lifting it, returning the room's name. Held to the
+	/// same rules as muting, the user may have gone offline since so their role is looked up by name.
+	pub fn unmute(&mut self, token: Token, target_name: &Username) -> Result<Roomname, String> {
+		let room_name = try!(self.get_location(token).ok_or("Select a username first".to_string()));
+		match self.get_token(target_name) {
+			Some(target) => {
+				try!(self.check_can_moderate(token, target, &room_name));
+			},
+			None if !self.is_operator(token) => {
+				let room = &self.rooms[&room_name];
+				let target_key = username_key(target_name);
+				if self.operators.contains(&target_key) || room.role_of(&target_key) >= self.get_room_role(token, &room_name) {
+					return Err("You can only do that to users whose role is below your own".into());
+				}
+			},
+			None => {}
+		}
+
+		let room = self.rooms.get_mut(&room_name).unwrap();
+		match room.mutes.remove(&username_key(target_name)) {
+			Some(_) => Ok(room_name),
+			None => Err(format!("{} isn't muted in room {}", target_name, room_name))
+		}
+	}
+
+	/// Can the user send messages to the room, everyone can unless it is read only. Operators and the
+	/// room's voiced users and above always can.
+	pub fn can_speak(&self, token: Token, room_name: &Roomname) -> bool {
+		match self.rooms.get(room_name) {
+			Some(room) if room.read_only => self.is_operator(token) || self.get_room_role(token, room_name) >= RoomRole::Voiced,
+			_ => true
+		}
+	}
+
+	/// Is the user muted in the room
+	pub fn is_muted(&mut self, token: Token, room_name: &Roomname) -> bool {
+		let user_key = match self.users.get(&token) {
+			Some(user) => username_key(&user.user_name),
+			None => {
+				return false;
+			}
+		};
+
+		let room = match self.rooms.get_mut(room_name) {
+			Some(room) => room,
+			None => {
+				return false;
+			}
+		};
+
+		if room.is_muted(&user_key) {
+			return true;
+		}
+
+		// Forget mutes that have run out
+		room.mutes.remove(&user_key);
+		false
+	}
+
+	/// Moderators can only act on users whose role in the room is below their own, and only operators
+	/// can act on operators.
+	fn check_can_moderate(&self, token: Token, target: Token, room_name: &Roomname) -> Result<(), String> {
+		if self.is_operator(token) {
+			return Ok(());
+		}
+
+		if self.is_operator(target) || self.get_room_role(target, room_name) >= self.get_room_role(token, room_name) {
+			return Err("You can only do that to users whose role is below your own".into());
+		}
+		Ok(())
+	}
+
+	/// Returns why the user can't be in the room if they are banned from it. Operators and the room's
+	/// moderators and owners are never banned.
+	fn check_ban(&self, user_name: &Username, is_operator: bool, address: Option<IpAddr>, room_name: &Roomname) -> Result<(), String> {
+		if is_operator {
+			return Ok(());
+		}
+
+		if let Some(room) = self.rooms.get(room_name) {
+			if room.role_of(&username_key(user_name)) >= RoomRole::Moderator {
+				return Ok(());
+			}
+		}
+
+		match self.bans.find(room_name, user_name, address) {
+			Some(_) => Err(format!("You are banned from room {}", room_name)),
+			None => Ok(())
+		}
+	}
+
+	/// Returns the user's presence, see `ChatUser::current_presence`.
+	pub fn get_presence(&self, token: Token) -> Option<Presence> {
+		self.users.get(&token).map(|user| user.current_presence(self.limits.auto_away_after))
+	}
+
+	/// Mark the user as away until they come back, replacing any away message they already had.
+	pub fn set_away(&mut self, token: Token, message: Option<String>) -> Result<(), String> {
+		match self.users.get_mut(&token) {
+			Some(user) => {
+				user.presence = Presence::Away { message: message, since: time::get_time() };
+				Ok(())
+			},
+			None => Err("Select a username first".into())
+		}
+	}
+
+	pub fn set_back(&mut self, token: Token) -> Result<(), String> {
+		let user = match self.users.get_mut(&token) {
+			Some(user) => user,
+			None => {
+				return Err("Select a username first".into());
+			}
+		};
+
+		if user.presence == Presence::Here {
+			return Err("You aren't away".into());
+		}
+		user.presence = Presence::Here;
+		Ok(())
+	}
+
+	/// Record that the user just sent something, resetting their idle time.
+	pub fn mark_active(&mut self, token: Token) {
+		if let Some(user) = self.users.get_mut(&token) {
+			user.last_active = time::get_time();
+		}
+	}
+
+	/// Returns the token of the connection registered under the given username, or a name that looks like it.
+	pub fn get_token(&self, user_name: &Username) -> Option<Token> {
+		self.user_name_lookup.get(&username_key(user_name)).cloned()
+	}
+
+	/// Add a message the user is sending to one of their rooms to that room's history.
+	pub fn record_message(&mut self, token: Token, room_name: &Roomname, body: &str) -> Option<HistoryEntry> {
+		match self.users.get(&token) {
+			Some(user) if user.rooms.contains(room_name) => {
+				let entry = self.history.record(room_name, &user.user_name, body);
+				if let Some(room) = self.rooms.get_mut(room_name) {
+					room.last_active = entry.timestamp;
+				}
+				Some(entry)
+			},
+			_ => None
+		}
+	}
+
+	/// Returns up to the last `count` messages sent to the room, oldest first.
+	pub fn get_history(&self, room_name: &Roomname, count: usize) -> Vec<HistoryEntry> {
+		self.history.last(room_name, count)
+	}
+
+	/// Add a room to the ones the user is in and focus it, returning the room's most recent messages so
+	/// they can catch up. `key` is checked if the room needs one. Joining a room the user is already in
+	/// just focuses it.
+	pub fn join_room(&mut self, token: Token, dest: &Roomname, key: Option<&str>) -> Result<Vec<HistoryEntry>, String> {
+		{
+			let user = &self.users[&token];
+			if !user.rooms.contains(dest) && user.rooms.len() >= self.limits.max_rooms_per_user {
+				return Err(format!("You can't be in more than {} rooms at once, /part one first", self.limits.max_rooms_per_user));
+			}
+
+			try!(self.check_ban(&user.user_name, user.role == ServerRole::Operator, self.addresses.get(&token).cloned(), dest));
+
+			// Operators, anyone with a role in the room and anyone invited to it can always join
+			if let Some(room) = self.rooms.get(dest) {
+				let user_key = username_key(&user.user_name);
+				let exempt = user.role == ServerRole::Operator || room.role_of(&user_key) > RoomRole::Member ||
+					room.invites.contains(&user_key) || room.members.contains(&token);
+
+				if !exempt && room.invite_only {
+					return Err(format!("Room {} is invite only", dest));
+				}
+				if !exempt && room.key.is_some() && room.key.as_ref().map(|room_key| room_key.as_str()) != key {
+					return Err(format!("Room {} needs a key, use /join {} KEY", dest, dest));
+				}
+			}
+		}
+
+		// Create the room if it doesn't exist yet, whoever creates it owns it
+		if self.ensure_room(dest, false) {
+			let user_key = username_key(&self.users[&token].user_name);
+			self.rooms.get_mut(dest).unwrap().set_role(user_key, RoomRole::Owner);
+		}
+
+		{
+			let user = self.users.get_mut(&token).unwrap();
+			if !user.rooms.contains(dest) {
+				user.rooms.push(dest.clone());
+			}
+			user.location = dest.clone();
+
+			let room = self.rooms.get_mut(dest).unwrap();
+			room.join(token);
+			room.invites.remove(&username_key(&user.user_name));
+		}
+
+		Ok(self.history.last(dest, self.limits.join_backlog))
+	}
+
+	/// Take the user out of one of their rooms, returning the room they have focused afterwards. Everyone
+	/// has to be in at least one room, so the last one can't be left.
+	pub fn part_room(&mut self, token: Token, room_name: &Roomname) -> Result<Roomname, String> {
+		{
+			let user = match self.users.get_mut(&token) {
+				Some(user) => user,
+				None => {
+					return Err("Select a username first".into());
+				}
+			};
+
+			if !user.rooms.contains(room_name) {
+				return Err(format!("You aren't in room {}", room_name));
+			}
+			if user.rooms.len() == 1 {
+				return Err(format!("Room {} is the only room you are in, /join another before leaving it", room_name));
+			}
+
+			user.rooms.retain(|joined| joined != room_name);
+			if user.location == *room_name {
+				user.location = user.rooms.last().unwrap().clone();
+			}
+			self.rooms.get_mut(room_name).unwrap().leave(token);
+		}
+
+		self.expire_empty_rooms();
+		Ok(self.users[&token].location.clone())
+	}
+
+	/// Pick which of the user's rooms messages without a `#room` prefix go to.
+	pub fn focus_room(&mut self, token: Token, room_name: &Roomname) -> Result<(), String> {
+		let user = match self.users.get_mut(&token) {
+			Some(user) => user,
+			None => {
+				return Err("Select a username first".into());
+			}
+		};
+
+		if !user.rooms.contains(room_name) {
+			return Err(format!("You aren't in room {}, /join it first", room_name));
+		}
+		user.location = room_name.clone();
+		Ok(())
+	}
+
+	/// Change the username of a connection that already has one, returning the old name. The user
+	/// keeps their room and session, only the name they are known by changes.
+	pub fn rename_user(&mut self, token: Token, user_name: Username) -> Result<Username, String> {
+		self.expire_suspended_users();
 
-		// Create the room if it doesn't exist yet
-		if !self.rooms.contains_key(dest) {
-			self.rooms.insert(dest.clone(), ChatRoom::new(dest.clone()));
+		let old_name = match self.users.get(&token) {
+			Some(user) => user.user_name.clone(),
+			None => {
+				return Err("Select a username before changing it".into());
+			}
+		};
+
+		if old_name == user_name {
+			return Err(format!("You are already called {}", user_name));
 		}
 
-		let user = self.users.get_mut(&token).unwrap();
+		try!(self.name_policy.validate(&user_name));
+
+		// Changing how the same name is written, e.g. its case, can't clash with anyone else
+		if username_key(&user_name) != username_key(&old_name) {
+			if self.accounts.contains(&user_name) {
+				return Err("That username belongs to a registered account, use /login NAME PASSWORD from a new connection".into());
+			}
+
+			if self.is_name_taken(&user_name) {
+				return Err("That username is taken, please try another".into());
+			}
+		}
+
+		self.user_name_lookup.remove(&username_key(&old_name));
+		self.user_name_lookup.insert(username_key(&user_name), token);
+
+		// Roles, mutes and invites follow the user to their new name. Those of a registered account stay
+		// with it for its next /login, otherwise `forget_roles` would drop them along with the new name.
+		// Mutes and invites apply under the new name as well, so /nick can't be used to dodge a mute
+		let (old_key, new_key) = (username_key(&old_name), username_key(&user_name));
+		let keep_old = self.accounts.contains(&old_name);
+		for room in self.rooms.values_mut() {
+			if !keep_old {
+				if let Some(role) = room.roles.remove(&old_key) {
+					room.set_role(new_key.clone(), role);
+				}
+			}
+
+			let mute = if keep_old { room.mutes.get(&old_key).cloned() } else { room.mutes.remove(&old_key) };
+			if let Some(until) = mute {
+				room.mutes.insert(new_key.clone(), until);
+			}
+
+			let invited = if keep_old { room.invites.contains(&old_key) } else { room.invites.remove(&old_key) };
+			if invited {
+				room.invites.insert(new_key.clone());
+			}
+		}
 
-		self.rooms.get_mut(&user.location).unwrap().members.remove(&token);
+		self.users.get_mut(&token).unwrap().user_name = user_name;
 
-		user.location = dest.clone();
-		self.rooms.get_mut(dest).unwrap().members.insert(token);
+		Ok(old_name)
 	}
 
-	/// Returns true if the user was registered, false otherwise.
+	/// Claim a username for the connection without a password. Names that belong to an account
+	/// can only be claimed through `login`.
 	pub fn register_user(&mut self, token: Token, user_name: Username) -> Result<(), String> {
+		try!(self.name_policy.validate(&user_name));
+
+		if self.accounts.contains(&user_name) {
+			return Err("That username belongs to a registered account, use /login NAME PASSWORD".into());
+		}
+
+		self.add_user(token, user_name)
+	}
+
+	/// Check that the connection can register the username, before its password is hashed. A connection
+	/// without a username can register any free name, a connection that already has one only that name.
+	pub fn check_can_register(&self, token: Token, user_name: &Username) -> Result<(), String> {
+		match self.users.get(&token) {
+			Some(user) => {
+				if user.user_name != *user_name {
+					return Err("You can only register the username you are currently using".into());
+				}
+			},
+			None => {
+				try!(self.name_policy.validate(user_name));
+				if self.is_name_taken(user_name) {
+					return Err("That username is taken, please try another".into());
+				}
+
+				// Check before the account is created, so being banned doesn't leave a registration half done
+				try!(self.check_ban(user_name, false, self.addresses.get(&token).cloned(), &self.default_room));
+			}
+		}
+
+		if self.accounts.contains(user_name) {
+			return Err("That username is already registered to an account".into());
+		}
+
+		// Whoever registered an operator's name would become an operator on their next /login. Operator
+		// accounts are created from operator_passwords instead
+		if self.operators.contains(&username_key(user_name)) {
+			return Err("That username belongs to a server operator and can't be registered".into());
+		}
+		Ok(())
+	}
+
+	/// Create a password protected account for the username once its password has been hashed. A
+	/// connection without a username is logged in under the new account.
+	pub fn register_account(&mut self, token: Token, user_name: Username, hash: String) -> Result<(), String> {
+		// Someone else may have taken the name while the password was being hashed
+		try!(self.check_can_register(token, &user_name));
+
+		let logged_in = self.users.contains_key(&token);
+		try!(self.accounts.create(&user_name, hash));
+
+		if !logged_in {
+			try!(self.add_user(token, user_name));
+		}
+		Ok(())
+	}
+
+	/// Returns the name the account that looks like the username was registered under and the hash of
+	/// its password, or None if there is no such account.
+	pub fn get_account(&self, user_name: &Username) -> Option<(Username, String)> {
+		let user_name = self.accounts.canonical_name(user_name).unwrap_or(user_name.clone());
+		self.accounts.hash_of(&user_name).map(|hash| (user_name, hash))
+	}
+
+	/// Log the connection in to a registered account, once its password has been checked. If the
+	/// account's last connection dropped and its session is still held, the session is picked back up
+	/// as if with /resume and the messages it missed are returned.
+	pub fn login(&mut self, token: Token, user_name: Username) -> Result<Option<Vec<Rc<Vec<u8>>>>, String> {
+		self.expire_suspended_users();
+
+		if self.users.contains_key(&token) {
+			return Err("You are already logged in".into());
+		}
+
+		let key = username_key(&user_name);
+		let suspended = self.suspended_users.iter()
+			.find(|&(_, suspended)| username_key(&suspended.user.user_name) == key)
+			.map(|(resume_token, _)| resume_token.clone());
+
+		let missed_messages = match suspended {
+			Some(resume_token) => Some(try!(self.resume(token, &resume_token))),
+			None => {
+				try!(self.add_user(token, user_name));
+				None
+			}
+		};
+		self.grant_operator(token);
+		Ok(missed_messages)
+	}
+
+	/// Pick a suspended session back up on a new connection, giving it a new resume token. Returns the
+	/// messages that were sent to the user's rooms while they were away.
+	pub fn resume(&mut self, token: Token, resume_token: &str) -> Result<Vec<Rc<Vec<u8>>>, String> {
+		self.expire_suspended_users();
+
+		if self.users.contains_key(&token) {
+			return Err("You are already logged in".into());
+		}
+
+		let suspended = match self.suspended_users.remove(resume_token) {
+			Some(suspended) => suspended,
+			None => {
+				return Err("That resume token is invalid or has expired".into());
+			}
+		};
+
+		let mut user = suspended.user;
+		user.id = token;
+		user.last_active = time::get_time();
+
+		// Rooms the user was banned from while they were away are left out. If that is all of them they
+		// are put back in the default room instead
+		let address = self.addresses.get(&token).cloned();
+		let is_operator = user.role == ServerRole::Operator;
+		let allowed: Vec<Roomname> = user.rooms.iter()
+			.filter(|room_name| self.check_ban(&user.user_name, is_operator, address, room_name).is_ok())
+			.cloned()
+			.collect();
+
+		if allowed.is_empty() {
+			if let Err(e) = self.check_ban(&user.user_name, is_operator, address, &self.default_room) {
+				self.suspended_users.insert(resume_token.to_string(), SuspendedUser {
+					user: user,
+					since: suspended.since,
+					missed_messages: suspended.missed_messages
+				});
+				return Err(e);
+			}
+			user.rooms = vec![self.default_room.clone()];
+		} else {
+			user.rooms = allowed;
+		}
+
+		if !user.rooms.contains(&user.location) {
+			user.location = user.rooms[0].clone();
+		}
+
+		// The rooms may have been created by someone else's /join, so make sure they still exist
+		for room_name in user.rooms.iter() {
+			self.ensure_room(room_name, false);
+			self.rooms.get_mut(room_name).unwrap().join(token);
+		}
+		// Each token only works once, in case someone else saw it
+		user.resume_token = new_resume_token();
+
+		self.user_name_lookup.insert(username_key(&user.user_name), token);
+		self.users.insert(token, user);
+
+		return Ok(suspended.missed_messages.into_iter().collect());
+	}
+
+	pub fn get_resume_token(&self, token: Token) -> Option<String> {
+		self.users.get(&token).map(|user| user.resume_token.clone())
+	}
+
+	/// Keep a copy of a message sent to the room for every suspended user in it, so it can be replayed if they /resume.
+	pub fn record_missed_message(&mut self, room_name: &Roomname, message: Rc<Vec<u8>>) {
+		if self.limits.max_missed_messages == 0 {
+			return;
+		}
+
+		for suspended in self.suspended_users.values_mut() {
+			if suspended.user.rooms.contains(room_name) {
+				if suspended.missed_messages.len() >= self.limits.max_missed_messages {
+					suspended.missed_messages.pop_front();
+				}
+				suspended.missed_messages.push_back(message.clone());
+			}
+		}
+	}
+
+	/// Make the user an operator if their account is listed in the `operators` setting. Only called from
+	/// /login, once they have proven they own an account that already existed.
+	fn grant_operator(&mut self, token: Token) {
+		let operators = &self.operators;
+		if let Some(user) = self.users.get_mut(&token) {
+			if operators.contains(&username_key(&user.user_name)) {
+				super::log_at(LogLevel::Info, format!("{} logged in as a server operator", user.user_name));
+				user.role = ServerRole::Operator;
+			}
+		}
+	}
+
+	/// Drop every room role, mute and invite held by the name, unless it belongs to a registered account.
+	/// Otherwise whoever picks the name next would get them.
+	fn forget_roles(&mut self, user_name: &Username) {
+		if self.accounts.contains(user_name) {
+			return;
+		}
+
+		let key = username_key(user_name);
+		for room in self.rooms.values_mut() {
+			room.roles.remove(&key);
+			room.mutes.remove(&key);
+			room.invites.remove(&key);
+		}
+	}
+
+	/// Create the room if it doesn't exist yet, returning true if it was created. A room that had
+	/// messages before a restart picks up its last activity time from the history.
+	fn ensure_room(&mut self, room_name: &Roomname, persistent: bool) -> bool {
+		if self.rooms.contains_key(room_name) {
+			return false;
+		}
+
+		let mut room = ChatRoom::new(room_name.clone(), persistent);
+		if let Some(entry) = self.history.last(room_name, 1).pop() {
+			room.last_active = entry.timestamp;
+		}
+		self.rooms.insert(room_name.clone(), room);
+		true
+	}
+
+	fn add_user(&mut self, token: Token, user_name: Username) -> Result<(), String> {
+		self.expire_suspended_users();
+
 		if self.users.contains_key(&token) {
 			return Err("A user is already registered for that token".into());
 		}
 
-		// This is not correctly detecting collisions yet
-		if self.user_name_lookup.get(&user_name).is_some() {
-			println!("Collision");
-			return Err("A user with that name is already registered".into());
+		if self.is_name_taken(&user_name) {
+			return Err("That username is taken, please try another".into());
 		}
 
+		// Operators are only made once they have logged in, so the name has to belong to an account too
+		let is_operator = self.operators.contains(&username_key(&user_name)) && self.accounts.contains(&user_name);
+		try!(self.check_ban(&user_name, is_operator, self.addresses.get(&token).cloned(), &self.default_room));
+
 		let user = ChatUser {
 			id: token,
 			user_name: user_name.clone(),
-			location: "default".into()
+			rooms: vec![self.default_room.clone()],
+			location: self.default_room.clone(),
+			role: ServerRole::User,
+			last_active: time::get_time(),
+			presence: Presence::Here,
+			resume_token: new_resume_token()
 		};
 
-		self.rooms.get_mut("default".into()).unwrap().members.insert(token);
+		self.rooms.get_mut(&self.default_room).unwrap().join(token);
 		self.users.insert(token, user);
-		self.user_name_lookup.insert(user_name, token);
+		self.user_name_lookup.insert(username_key(&user_name), token);
 
 		return Ok(());
 	}
 
-	pub fn remove_user(&mut self, token: Token) {
+	/// Names of suspended users are still reserved until they either /resume or their grace period runs out.
+	fn is_name_taken(&self, user_name: &Username) -> bool {
+		let key = username_key(user_name);
+		self.user_name_lookup.contains_key(&key) ||
+			self.suspended_users.values().any(|suspended| username_key(&suspended.user.user_name) == key)
+	}
+
+	/// Drop suspended sessions whose grace period has run out, freeing up their usernames.
+	fn expire_suspended_users(&mut self) {
+		let now = time::get_time();
+		let grace_period = self.limits.resume_grace_period;
+		let expired: Vec<String> = self.suspended_users.iter()
+			.filter(|&(_, suspended)| (now - suspended.since).num_seconds() >= grace_period)
+			.map(|(resume_token, _)| resume_token.clone())
+			.collect();
+
+		for resume_token in expired {
+			if let Some(suspended) = self.suspended_users.remove(&resume_token) {
+				self.forget_roles(&suspended.user.user_name);
+			}
+		}
+	}
+
+	/// Returns how many seconds until the next empty room is due to be removed, or None if there are
+	/// no empty ephemeral rooms. Rooms a suspended user is in are due once the user's session has
+	/// expired as well.
+	pub fn next_room_expiry(&self) -> Option<u64> {
+		let now = time::get_time();
+		self.rooms.values()
+			.filter(|room| !room.persistent && room.members.is_empty() && room.name != self.default_room)
+			.filter_map(|room| room.empty_since.map(|since| {
+				let mut due = since.sec + self.limits.empty_room_grace_period;
+				for suspended in self.suspended_users.values().filter(|suspended| suspended.user.rooms.contains(&room.name)) {
+					due = ::std::cmp::max(due, suspended.since.sec + self.limits.resume_grace_period);
+				}
+				::std::cmp::max(due - now.sec, 0) as u64
+			}))
+			.min()
+	}
+
+	/// Remove ephemeral rooms that have been empty for the grace period. A room that a suspended user
+	/// is in isn't empty, they may still /resume into it.
+	pub fn expire_empty_rooms(&mut self) {
+		self.expire_suspended_users();
+
+		let now = time::get_time();
+		let grace_period = self.limits.empty_room_grace_period;
+		let expired: Vec<Roomname> = self.rooms.values()
+			.filter(|room| !room.persistent && room.members.is_empty() && room.name != self.default_room)
+			.filter(|room| room.empty_since.map(|since| (now - since).num_seconds() >= grace_period).unwrap_or(false))
+			.filter(|room| !self.suspended_users.values().any(|suspended| suspended.user.rooms.contains(&room.name)))
+			.map(|room| room.name.clone())
+			.collect();
+
+		for room_name in expired.iter() {
+			super::log_at(LogLevel::Debug, format!("Removing empty room {}", room_name));
+			self.rooms.remove(room_name);
+		}
+		self.history.forget(&expired);
+	}
+
+	/// Remove a user who left on purpose, their username is freed up immediately.
+	pub fn remove_user(&mut self, token: Token) -> Option<ChatUser> {
+		let user = self.take_user(token);
+		if let Some(ref user) = user {
+			self.forget_roles(&user.user_name);
+		}
+		self.expire_empty_rooms();
+		user
+	}
+
+	/// Take the user out of their rooms and the lookups, without cleaning up rooms that are now empty.
+	fn take_user(&mut self, token: Token) -> Option<ChatUser> {
+		self.addresses.remove(&token);
 		match self.users.remove(&token) {
 			Some(user) => {
-				self.rooms.get_mut(&user.location).unwrap().members.remove(&token);
-				self.user_name_lookup.remove(&user.user_name);
+				for room_name in user.rooms.iter() {
+					self.rooms.get_mut(room_name).unwrap().leave(token);
+				}
+				self.user_name_lookup.remove(&username_key(&user.user_name));
+				Some(user)
 			},
-			None => {}
+			None => None
 		}
 	}
-}
\ No newline at end of file
+
+	/// Remove a user whose connection dropped, holding on to their session so they can /resume it.
+	pub fn suspend_user(&mut self, token: Token) {
+		self.expire_suspended_users();
+
+		// Their rooms are kept for them until their grace period runs out too
+		if let Some(user) = self.take_user(token) {
+			self.suspended_users.insert(user.resume_token.clone(), SuspendedUser {
+				user: user,
+				since: time::get_time(),
+				missed_messages: VecDeque::new()
+			});
+		}
+	}
+}
+
+/// A secret for the user to /resume their session with.
+fn new_resume_token() -> String {
+	thread_rng().gen_ascii_chars().take(24).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::env;
+	use std::fs;
+	use std::path::PathBuf;
+	use mio::Token;
+	use rand::{thread_rng, Rng};
+	use super::ChatApp;
+	use super::super::accounts::AccountStore;
+	use super::super::bans::BanStore;
+	use super::super::command::ChatCommand;
+	use super::super::config::ServerConfig;
+	use super::super::history::HistoryStore;
+	use super::super::roles::{RoomRole, ServerRole};
+	use super::super::room_store::RoomStore;
+	use super::super::user::username_key;
+
+	const MEMBER: Token = Token(1);
+	const VOICED: Token = Token(2);
+	const MODERATOR: Token = Token(3);
+	const OWNER: Token = Token(4);
+	const OPERATOR: Token = Token(5);
+
+	/// A directory for the stores' files, removed once the test is done with it
+	struct TestDir(PathBuf);
+
+	impl Drop for TestDir {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	/// An app with a user of every role in the default room, each named after their role
+	fn test_app() -> (ChatApp, TestDir) {
+		let name: String = thread_rng().gen_ascii_chars().take(12).collect();
+		let dir = TestDir(env::temp_dir().join(format!("simple_chat_test_{}", name)));
+		fs::create_dir(&dir.0).unwrap();
+
+		let config = ServerConfig::default();
+		let mut app = ChatApp::new(&config,
+			AccountStore::load(dir.0.join("accounts.txt")).unwrap(),
+			HistoryStore::load(dir.0.join("history.log"), 10).unwrap(),
+			BanStore::load(dir.0.join("bans.txt")).unwrap(),
+			RoomStore::load(dir.0.join("rooms.txt")).unwrap());
+
+		let users = [(MEMBER, "member", RoomRole::Member), (VOICED, "voiced", RoomRole::Voiced),
+			(MODERATOR, "moderator", RoomRole::Moderator), (OWNER, "owner", RoomRole::Owner), (OPERATOR, "operator", RoomRole::Member)];
+		for &(token, name, role) in users.iter() {
+			app.register_user(token, name.to_string()).unwrap();
+			app.rooms.get_mut(&config.default_room).unwrap().set_role(username_key(&name.to_string()), role);
+		}
+		app.users.get_mut(&OPERATOR).unwrap().role = ServerRole::Operator;
+		(app, dir)
+	}
+
+	#[test]
+	fn moderation_commands_need_moderator() {
+		let (app, _dir) = test_app();
+		let commands = [
+			ChatCommand::Kick("member".to_string(), None),
+			ChatCommand::Ban("member".to_string(), None),
+			ChatCommand::Unban("member".to_string())
+		];
+		for command in commands.iter() {
+			assert!(app.check_permission(MEMBER, command.permission()).is_err());
+			assert!(app.check_permission(VOICED, command.permission()).is_err());
+			assert!(app.check_permission(MODERATOR, command.permission()).is_ok());
+			assert!(app.check_permission(OWNER, command.permission()).is_ok());
+			assert!(app.check_permission(OPERATOR, command.permission()).is_ok());
+		}
+	}
+
+	#[test]
+	fn moderate_only_below_own_role() {
+		let (app, _dir) = test_app();
+		let room = "default".to_string();
+		assert!(app.check_can_moderate(MODERATOR, MEMBER, &room).is_ok());
+		assert!(app.check_can_moderate(MODERATOR, VOICED, &room).is_ok());
+		assert!(app.check_can_moderate(MODERATOR, MODERATOR, &room).is_err());
+		assert!(app.check_can_moderate(MODERATOR, OWNER, &room).is_err());
+		assert!(app.check_can_moderate(OWNER, MODERATOR, &room).is_ok());
+		assert!(app.check_can_moderate(OWNER, OPERATOR, &room).is_err());
+		assert!(app.check_can_moderate(OPERATOR, OWNER, &room).is_ok());
+		assert!(app.check_can_moderate(MEMBER, VOICED, &room).is_err());
+	}
+
+	#[test]
+	fn kick() {
+		let (mut app, _dir) = test_app();
+		assert_eq!(app.kick(MODERATOR, &"member".to_string()), Ok(("default".to_string(), MEMBER)));
+		assert!(app.kick(MODERATOR, &"owner".to_string()).is_err());
+		assert!(app.kick(MODERATOR, &"nobody".to_string()).is_err());
+		assert!(app.kick(OPERATOR, &"operator".to_string()).is_err());
+	}
+
+	#[test]
+	fn ban_and_unban() {
+		let (mut app, _dir) = test_app();
+		assert!(app.ban(MODERATOR, "owner", None).is_err());
+		assert_eq!(app.ban(MODERATOR, "member", None), Ok(("default".to_string(), vec![MEMBER])));
+		assert_eq!(app.unban(MODERATOR, "member"), Ok("default".to_string()));
+		assert!(app.unban(MODERATOR, "member").is_err());
+	}
+
+	#[test]
+	fn mute_and_unmute() {
+		let room = "default".to_string();
+		let (mut app, _dir) = test_app();
+		assert!(app.mute(MODERATOR, &"owner".to_string(), None).is_err());
+		assert_eq!(app.mute(MODERATOR, &"MEMBER".to_string(), None), Ok((room.clone(), "member".to_string())));
+		assert!(app.is_muted(MEMBER, &room));
+
+		assert!(app.mute(OWNER, &"moderator".to_string(), Some(60)).is_ok());
+		assert!(app.unmute(MODERATOR, &"moderator".to_string()).is_err());
+		assert!(app.is_muted(MODERATOR, &room));
+
+		assert_eq!(app.unmute(MODERATOR, &"member".to_string()), Ok(room.clone()));
+		assert!(!app.is_muted(MEMBER, &room));
+		assert!(app.unmute(MODERATOR, &"member".to_string()).is_err());
+	}
+
+	#[test]
+	fn unmute_offline_user() {
+		let room = "default".to_string();
+		let (mut app, _dir) = test_app();
+		assert!(app.mute(OWNER, &"moderator".to_string(), None).is_ok());
+		assert!(app.mute(OWNER, &"member".to_string(), None).is_ok());
+		app.rooms.get_mut(&room).unwrap().set_role(username_key(&"voiced".to_string()), RoomRole::Moderator);
+
+		// Roles and mutes are only kept for names that belong to an account once their user is gone
+		app.register_account(MODERATOR, "moderator".to_string(), "hash".to_string()).unwrap();
+		app.register_account(MEMBER, "member".to_string(), "hash".to_string()).unwrap();
+		app.remove_user(MODERATOR);
+		app.remove_user(MEMBER);
+
+		assert!(app.unmute(VOICED, &"moderator".to_string()).is_err());
+		assert_eq!(app.unmute(VOICED, &"member".to_string()), Ok(room));
+	}
+}
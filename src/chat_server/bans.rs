@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use time::{self, Timespec};
+
+use super::access::IpRange;
+use super::config::LogLevel;
+use super::glob;
+use super::room::Roomname;
+use super::user::{username_key, Username};
+
+/// Who a ban applies to, worked out from how the mask given to /ban is written.
+#[derive(Clone, Debug)]
+pub enum BanMask {
+    /// A single username or account, compared by lookup key so look-alike names are banned too
+    Name(String),
+
+    /// A username pattern where `*` and `?` are wildcards, e.g. `spam*`
+    Pattern(String),
+
+    /// An address or a range of them in CIDR notation, e.g. `10.0.0.0/24`
+    Address(IpRange)
+}
+
+impl BanMask {
+    pub fn parse(mask: &str) -> BanMask {
+        if let Ok(range) = IpRange::parse(mask) {
+            return BanMask::Address(range);
+        }
+
+        if mask.contains('*') || mask.contains('?') {
+            BanMask::Pattern(username_key(mask))
+        } else {
+            BanMask::Name(username_key(mask))
+        }
+    }
+
+    pub fn matches(&self, user_name: &Username, address: Option<IpAddr>) -> bool {
+        match *self {
+            BanMask::Name(ref key) => *key == username_key(user_name),
+            BanMask::Pattern(ref pattern) => glob::matches(pattern, &username_key(user_name)),
+            BanMask::Address(ref range) => address.map(|address| range.contains(address)).unwrap_or(false)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Ban {
+    pub room: Roomname,
+
+    /// The mask as it was given to /ban, which is also what /unban takes
+    pub mask: String,
+
+    pub target: BanMask,
+
+    /// Where the user was connecting from if they were online when a username was banned, so they
+    /// can't come straight back under another name
+    pub address: Option<IpAddr>,
+
+    /// When the ban runs out, None if it never does
+    pub expires: Option<Timespec>,
+
+    pub set_by: Username
+}
+
+impl Ban {
+    fn is_expired(&self, now: Timespec) -> bool {
+        self.expires.map(|expires| expires <= now).unwrap_or(false)
+    }
+
+    fn matches(&self, user_name: &Username, address: Option<IpAddr>) -> bool {
+        self.target.matches(user_name, address) || (self.address.is_some() && self.address == address)
+    }
+}
+
+/// Users, username patterns and addresses banned from rooms, persisted to disk so bans survive restarts.
+///
+/// The file holds one ban per line: the room, the mask, the banned user's address or `-`, when the ban
+/// expires in seconds since the epoch (0 if it never does) and who set it, separated by spaces. None of
+/// them can contain whitespace. The whole file is rewritten whenever a ban is added or lifted, expired
+/// bans are dropped at that point.
+pub struct BanStore {
+    path: PathBuf,
+
+    bans: Vec<Ban>
+}
+
+impl BanStore {
+    /// Load the bans from the given file. A missing file is treated as no bans and will be created when
+    /// the first one is set.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<BanStore> {
+        let mut store = BanStore {
+            path: path.as_ref().to_path_buf(),
+            bans: Vec::new()
+        };
+
+        let file = match File::open(&store.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(store);
+            },
+            Err(e) => {
+                return Err(e);
+            }
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 5 {
+                super::log_at(LogLevel::Warn, format!("Skipping malformed line in bans: {}", line));
+                continue;
+            }
+
+            let address = match fields[2] {
+                "-" => Ok(None),
+                address => address.parse::<IpAddr>().map(Some)
+            };
+
+            match (address, fields[3].parse::<i64>()) {
+                (Ok(address), Ok(seconds)) => {
+                    store.bans.push(Ban {
+                        room: fields[0].to_string(),
+                        mask: fields[1].to_string(),
+                        target: BanMask::parse(fields[1]),
+                        address: address,
+                        expires: if seconds == 0 { None } else { Some(Timespec::new(seconds, 0)) },
+                        set_by: fields[4].to_string()
+                    });
+                },
+                _ => {
+                    super::log_at(LogLevel::Warn, format!("Skipping malformed line in bans: {}", line));
+                }
+            }
+        }
+
+        Ok(store)
+    }
+
+    /// Returns the ban keeping the user out of the room, if there is one that hasn't expired.
+    pub fn find(&self, room_name: &Roomname, user_name: &Username, address: Option<IpAddr>) -> Option<&Ban> {
+        let now = time::get_time();
+        self.bans.iter().find(|ban| ban.room == *room_name && !ban.is_expired(now) && ban.matches(user_name, address))
+    }
+
+    /// Ban a mask from the room, replacing any ban the room already has for the same mask.
+    pub fn add(&mut self, ban: Ban) -> Result<(), String> {
+        self.bans.retain(|existing| existing.room != ban.room || existing.mask != ban.mask);
+        self.bans.push(ban);
+        self.save()
+    }
+
+    /// Lift every ban on the mask in the room, returning false if there weren't any.
+    pub fn remove(&mut self, room_name: &Roomname, mask: &str) -> Result<bool, String> {
+        let count = self.bans.len();
+        self.bans.retain(|ban| ban.room != *room_name || ban.mask != mask);
+        if self.bans.len() == count {
+            return Ok(false);
+        }
+
+        try!(self.save());
+        Ok(true)
+    }
+
+    /// Rewrite the file with every ban that hasn't expired yet.
+    fn save(&mut self) -> Result<(), String> {
+        let now = time::get_time();
+        self.bans.retain(|ban| !ban.is_expired(now));
+
+        let bans = &self.bans;
+        let saved = File::create(&self.path).and_then(|mut file| {
+            for ban in bans.iter() {
+                let address = ban.address.map(|address| address.to_string()).unwrap_or("-".to_string());
+                try!(writeln!(file, "{} {} {} {} {}", ban.room, ban.mask, address, ban.expires.map(|expires| expires.sec).unwrap_or(0), ban.set_by));
+            }
+            Ok(())
+        });
+
+        if let Err(e) = saved {
+            super::log_at(LogLevel::Error, format!("Failed to save bans {:?}, {:?}", self.path, e));
+            return Err("Failed to save the ban list, please try again later".into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use super::BanMask;
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    #[test]
+    fn masks_are_parsed_by_how_they_are_written() {
+        match BanMask::parse("Alice") {
+            BanMask::Name(ref key) => assert_eq!(key, "alice"),
+            ref mask => panic!("expected a name, got {:?}", mask)
+        }
+        match BanMask::parse("spam*") {
+            BanMask::Pattern(ref pattern) => assert_eq!(pattern, "spam*"),
+            ref mask => panic!("expected a pattern, got {:?}", mask)
+        }
+        match BanMask::parse("10.0.0.0/24") {
+            BanMask::Address(_) => {},
+            ref mask => panic!("expected an address, got {:?}", mask)
+        }
+    }
+
+    #[test]
+    fn names_match_case_and_look_alikes() {
+        let mask = BanMask::parse("alice");
+        assert!(mask.matches(&"ALICE".to_string(), None));
+        assert!(mask.matches(&"аlice".to_string(), None));
+        assert!(!mask.matches(&"alicex".to_string(), ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn patterns_match_by_lookup_key() {
+        let mask = BanMask::parse("Spam?*");
+        assert!(mask.matches(&"spambot".to_string(), None));
+        assert!(mask.matches(&"SPAM1".to_string(), None));
+        assert!(!mask.matches(&"spam".to_string(), None));
+        assert!(!mask.matches(&"notspam".to_string(), None));
+    }
+
+    #[test]
+    fn addresses_only_match_users_with_an_address_in_range() {
+        let mask = BanMask::parse("10.0.0.0/24");
+        assert!(mask.matches(&"bob".to_string(), ip("10.0.0.7")));
+        assert!(!mask.matches(&"bob".to_string(), ip("10.0.1.7")));
+        assert!(!mask.matches(&"bob".to_string(), None));
+    }
+}
@@ -1,9 +1,145 @@
+use super::roles::{Permission, RoomRole};
 
 
+
+/// How /rooms orders the rooms it lists.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RoomSort {
+	Name,
+
+	/// Most recently active first
+	Activity,
+
+	/// Most members first
+	Members
+}
+
+/// The arguments to /rooms: `/rooms [PATTERN] [-s name|activity|members] [-p PAGE]`.
+pub struct RoomListQuery {
+	/// Only list rooms whose name matches this glob, e.g. `ops-*`
+	pub pattern: Option<String>,
+
+	pub sort: RoomSort,
+
+	/// Which page of the listing to show, starting at 1
+	pub page: usize
+}
+
+impl RoomListQuery {
+	fn parse<'a, I: Iterator<Item = &'a str>>(mut args: I) -> Option<RoomListQuery> {
+		let mut query = RoomListQuery {
+			pattern: None,
+			sort: RoomSort::Name,
+			page: 1
+		};
+
+		while let Some(arg) = args.next() {
+			match arg {
+				"-s" => {
+					query.sort = match args.next() {
+						Some("name") => RoomSort::Name,
+						Some("activity") => RoomSort::Activity,
+						Some("members") => RoomSort::Members,
+						_ => {
+							return None;
+						}
+					};
+				},
+				"-p" => {
+					query.page = match args.next().and_then(|page| page.parse().ok()) {
+						Some(page) if page > 0 => page,
+						_ => {
+							return None;
+						}
+					};
+				},
+				pattern => {
+					// Only one pattern
+					if query.pattern.is_some() {
+						return None;
+					}
+					query.pattern = Some(pattern.to_string());
+				}
+			}
+		}
+
+		Some(query)
+	}
+}
+
+/// A change to the modes of a room, made with `/mode +i|-i|+k KEY|-k|+h|-h|+r|-r`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ModeChange {
+	InviteOnly(bool),
+	Key(Option<String>),
+	Hidden(bool),
+	ReadOnly(bool)
+}
+
+impl ModeChange {
+	fn parse<'a, I: Iterator<Item = &'a str>>(mut args: I) -> Option<ModeChange> {
+		let change = match (args.next(), args.next()) {
+			(Some("+i"), None) => ModeChange::InviteOnly(true),
+			(Some("-i"), None) => ModeChange::InviteOnly(false),
+			(Some("+k"), Some(key)) => ModeChange::Key(Some(key.to_string())),
+			(Some("-k"), None) => ModeChange::Key(None),
+			(Some("+h"), None) => ModeChange::Hidden(true),
+			(Some("-h"), None) => ModeChange::Hidden(false),
+			(Some("+r"), None) => ModeChange::ReadOnly(true),
+			(Some("-r"), None) => ModeChange::ReadOnly(false),
+			_ => {
+				return None;
+			}
+		};
+
+		// One change at a time
+		if args.next().is_some() {
+			return None;
+		}
+		Some(change)
+	}
+
+	/// How the change is shown to the room, without the key
+	pub fn name(&self) -> &'static str {
+		match *self {
+			ModeChange::InviteOnly(true) => "+i",
+			ModeChange::InviteOnly(false) => "-i",
+			ModeChange::Key(Some(_)) => "+k",
+			ModeChange::Key(None) => "-k",
+			ModeChange::Hidden(true) => "+h",
+			ModeChange::Hidden(false) => "-h",
+			ModeChange::ReadOnly(true) => "+r",
+			ModeChange::ReadOnly(false) => "-r"
+		}
+	}
+}
+
 pub enum ChatCommand {
-	ListRooms,
-	// ListRoomMembers(String), Todo
-	ChangeRoom(String),
+	ListRooms(RoomListQuery),
+	ListRoomMembers(Option<String>),
+	Join(String, Option<String>),
+	Part(Option<String>),
+	Focus(String),
+	ChangeName(String),
+	Topic(Option<String>),
+	SetRole(String, RoomRole),
+	Persist(bool),
+	Mode(Option<ModeChange>),
+	Invite(String),
+	Kick(String, Option<String>),
+	Ban(String, Option<u64>),
+	Unban(String),
+	Mute(String, Option<u64>),
+	Unmute(String),
+	PrivateMessage(String, String),
+	Register(String, String),
+	Login(String, String),
+	Resume(String),
+	History(Option<usize>),
+	Away(Option<String>),
+	Back,
+	Stats,
+	Pong,
 	Quit
 }
 
@@ -15,7 +151,16 @@ impl ChatCommand {
 
 		match split.next() {
 			Some("/rooms") => {
-				return Some(ChatCommand::ListRooms)
+				return RoomListQuery::parse(split).map(ChatCommand::ListRooms)
+			},
+			Some("/who") => {
+				return Some(ChatCommand::ListRoomMembers(split.next().map(|room_name| room_name.to_string())))
+			},
+			Some("/stats") => {
+				return Some(ChatCommand::Stats)
+			},
+			Some("/pong") => {
+				return Some(ChatCommand::Pong)
 			},
 			Some("/quit") => {
 				return Some(ChatCommand::Quit)
@@ -23,9 +168,170 @@ impl ChatCommand {
 			Some("/join") => {
 				match split.next() {
 					Some(room_name) => {
-						return Some(ChatCommand::ChangeRoom(room_name.to_string()))
+						return Some(ChatCommand::Join(room_name.to_string(), split.next().map(|key| key.to_string())))
 					},
-					// Missing the room name to change to
+					// Missing the room name to join
+					None => {
+						return None;
+					}
+				}
+			},
+			Some("/part") => {
+				return Some(ChatCommand::Part(split.next().map(|room_name| room_name.to_string())))
+			},
+			Some("/switch") | Some("/focus") => {
+				return split.next().map(|room_name| ChatCommand::Focus(room_name.to_string()))
+			},
+			Some("/nick") => {
+				match split.next() {
+					Some(user_name) => {
+						return Some(ChatCommand::ChangeName(user_name.to_string()))
+					},
+					// Missing the new username
+					None => {
+						return None;
+					}
+				}
+			},
+			Some("/topic") => {
+				return Some(ChatCommand::Topic(rest_of_line(command, 1).map(|text| text.to_string())))
+			},
+			Some("/role") => {
+				match (split.next(), split.next().and_then(RoomRole::parse)) {
+					(Some(user_name), Some(role)) => {
+						return Some(ChatCommand::SetRole(user_name.to_string(), role))
+					},
+					// Missing the username, or the role isn't one of member, voiced, moderator or owner
+					_ => {
+						return None;
+					}
+				}
+			},
+			Some("/persist") => {
+				match split.next() {
+					None | Some("on") => {
+						return Some(ChatCommand::Persist(true))
+					},
+					Some("off") => {
+						return Some(ChatCommand::Persist(false))
+					},
+					_ => {
+						return None;
+					}
+				}
+			},
+			Some("/mode") => {
+				let mut args = split.peekable();
+				if args.peek().is_none() {
+					return Some(ChatCommand::Mode(None))
+				}
+				return ModeChange::parse(args).map(|change| ChatCommand::Mode(Some(change)))
+			},
+			Some("/invite") => {
+				return split.next().map(|user_name| ChatCommand::Invite(user_name.to_string()))
+			},
+			Some("/kick") => {
+				match split.next() {
+					Some(user_name) => {
+						return Some(ChatCommand::Kick(user_name.to_string(), rest_of_line(command, 2).map(|reason| reason.to_string())))
+					},
+					// Missing the username
+					None => {
+						return None;
+					}
+				}
+			},
+			Some("/ban") => {
+				match (split.next(), split.next()) {
+					(Some(mask), None) => {
+						return Some(ChatCommand::Ban(mask.to_string(), None))
+					},
+					(Some(mask), Some(duration)) => {
+						// Not a valid command unless the duration is
+						return parse_duration(duration).map(|secs| ChatCommand::Ban(mask.to_string(), Some(secs)))
+					},
+					// Missing the username or mask
+					_ => {
+						return None;
+					}
+				}
+			},
+			Some("/unban") => {
+				return split.next().map(|mask| ChatCommand::Unban(mask.to_string()))
+			},
+			Some("/mute") => {
+				match (split.next(), split.next()) {
+					(Some(user_name), None) => {
+						return Some(ChatCommand::Mute(user_name.to_string(), None))
+					},
+					(Some(user_name), Some(duration)) => {
+						return parse_duration(duration).map(|secs| ChatCommand::Mute(user_name.to_string(), Some(secs)))
+					},
+					// Missing the username
+					_ => {
+						return None;
+					}
+				}
+			},
+			Some("/unmute") => {
+				return split.next().map(|user_name| ChatCommand::Unmute(user_name.to_string()))
+			},
+			Some("/msg") => {
+				match (split.next(), rest_of_line(command, 2)) {
+					(Some(user_name), Some(text)) => {
+						return Some(ChatCommand::PrivateMessage(user_name.to_string(), text.to_string()))
+					},
+					// Missing the recipient or the message body
+					_ => {
+						return None;
+					}
+				}
+			},
+			Some("/register") => {
+				match (split.next(), split.next()) {
+					(Some(user_name), Some(password)) => {
+						return Some(ChatCommand::Register(user_name.to_string(), password.to_string()))
+					},
+					// Missing the username or password
+					_ => {
+						return None;
+					}
+				}
+			},
+			Some("/login") => {
+				match (split.next(), split.next()) {
+					(Some(user_name), Some(password)) => {
+						return Some(ChatCommand::Login(user_name.to_string(), password.to_string()))
+					},
+					// Missing the username or password
+					_ => {
+						return None;
+					}
+				}
+			},
+			Some("/history") => {
+				match split.next() {
+					Some(count) => {
+						// Not a valid command unless the count is a number
+						return count.parse().ok().map(|count| ChatCommand::History(Some(count)))
+					},
+					None => {
+						return Some(ChatCommand::History(None))
+					}
+				}
+			},
+			Some("/away") => {
+				return Some(ChatCommand::Away(rest_of_line(command, 1).map(|message| message.to_string())))
+			},
+			Some("/back") => {
+				return Some(ChatCommand::Back)
+			},
+			Some("/resume") => {
+				match split.next() {
+					Some(resume_token) => {
+						return Some(ChatCommand::Resume(resume_token.to_string()))
+					},
+					// Missing the resume token
 					None => {
 						return None;
 					}
@@ -41,8 +347,128 @@ impl ChatCommand {
 			}
 		}
 	}
+
+	/// What the user needs before the command is run, checked by `ChatApp::check_permission`.
+	/// Commands that work on a room work on the room the user has focused.
+	pub fn permission(&self) -> Permission {
+		match *self {
+			ChatCommand::ListRooms(_) |
+			ChatCommand::ListRoomMembers(_) |
+			ChatCommand::Register(_, _) |
+			ChatCommand::Login(_, _) |
+			ChatCommand::Resume(_) |
+			ChatCommand::Pong |
+			ChatCommand::Quit => Permission::Anyone,
+
+			ChatCommand::Join(_, _) |
+			ChatCommand::Part(_) |
+			ChatCommand::Focus(_) |
+			ChatCommand::ChangeName(_) |
+			ChatCommand::PrivateMessage(_, _) |
+			ChatCommand::History(_) |
+			ChatCommand::Away(_) |
+			ChatCommand::Back |
+			ChatCommand::Topic(None) |
+			ChatCommand::Mode(None) => Permission::User,
+
+			ChatCommand::Topic(Some(_)) |
+			ChatCommand::Invite(_) => Permission::Room(RoomRole::Voiced),
+
+			ChatCommand::Mode(Some(_)) => Permission::Room(RoomRole::Moderator),
+
+			// Further limited to giving roles below your own, see `ChatApp::set_room_role`
+			ChatCommand::SetRole(_, _) => Permission::Room(RoomRole::Moderator),
+
+			// Further limited to users whose role is below your own, see `ChatApp::check_can_moderate`
+			ChatCommand::Kick(_, _) |
+			ChatCommand::Ban(_, _) |
+			ChatCommand::Unban(_) |
+			ChatCommand::Mute(_, _) |
+			ChatCommand::Unmute(_) => Permission::Room(RoomRole::Moderator),
+
+			ChatCommand::Stats |
+			ChatCommand::Persist(_) => Permission::Operator
+		}
+	}
 }
 
 pub fn is_command(message: &String) -> bool {
 	return message.starts_with('/');
-}
\ No newline at end of file
+}
+
+/// Parses a duration like `30s`, `10m`, `2h` or `7d` into seconds.
+fn parse_duration(duration: &str) -> Option<u64> {
+	let unit_secs = match duration.chars().last() {
+		Some('s') => 1,
+		Some('m') => 60,
+		Some('h') => 60 * 60,
+		Some('d') => 24 * 60 * 60,
+		_ => {
+			return None;
+		}
+	};
+
+	match duration[..duration.len() - 1].parse::<u64>() {
+		// Anything longer than about 68 years is as good as forever, and would overflow a timestamp
+		Ok(count) if count > 0 && count <= ::std::i32::MAX as u64 / unit_secs => Some(count * unit_secs),
+		_ => None
+	}
+}
+
+/// Returns everything after the first `skip` whitespace separated words of the line, with surrounding
+/// whitespace trimmed. Returns None if there is nothing left after skipping.
+fn rest_of_line(line: &str, skip: usize) -> Option<&str> {
+	let mut rest = line.trim_left();
+	for _ in 0..skip {
+		match rest.find(char::is_whitespace) {
+			Some(pos) => {
+				rest = rest[pos..].trim_left();
+			},
+			None => {
+				return None;
+			}
+		}
+	}
+
+	let rest = rest.trim_right();
+	if rest.is_empty() {
+		return None;
+	}
+	return Some(rest);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::parse_duration;
+
+	#[test]
+	fn durations_in_each_unit() {
+		assert_eq!(parse_duration("30s"), Some(30));
+		assert_eq!(parse_duration("10m"), Some(600));
+		assert_eq!(parse_duration("2h"), Some(7200));
+		assert_eq!(parse_duration("7d"), Some(7 * 24 * 60 * 60));
+	}
+
+	#[test]
+	fn invalid_durations() {
+		assert_eq!(parse_duration(""), None);
+		assert_eq!(parse_duration("s"), None);
+		assert_eq!(parse_duration("10"), None);
+		assert_eq!(parse_duration("10w"), None);
+		assert_eq!(parse_duration("0m"), None);
+		assert_eq!(parse_duration("-5m"), None);
+		assert_eq!(parse_duration("1.5h"), None);
+	}
+
+	#[test]
+	fn durations_too_long_for_a_timestamp_are_rejected() {
+		assert_eq!(parse_duration("2147483647s"), Some(2147483647));
+		assert_eq!(parse_duration("2147483648s"), None);
+		assert_eq!(parse_duration("100000d"), None);
+	}
+
+	#[test]
+	fn multibyte_units_are_rejected() {
+		assert_eq!(parse_duration("5é"), None);
+	}
+}
@@ -0,0 +1,794 @@
+use std::default::Default;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+
+use getopts::Options;
+use time;
+use toml;
+
+use super::access::{AccessList, IpRange};
+use super::room::Roomname;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug
+}
+
+impl LogLevel {
+    fn parse(name: &str) -> Option<LogLevel> {
+        match name {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None
+        }
+    }
+}
+
+/// What to do when a connection's send queue is full because the client isn't reading fast enough.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest queued messages to make room for new ones. A message that is partly written
+    /// can't be dropped, if that is all that is queued the new message is dropped instead
+    DropOldest,
+
+    /// Drop new messages until there is room, then tell the client how many were skipped
+    DropNewest,
+
+    /// Close the connection
+    Disconnect
+}
+
+impl SlowConsumerPolicy {
+    fn parse(name: &str) -> Option<SlowConsumerPolicy> {
+        match name {
+            "drop-oldest" => Some(SlowConsumerPolicy::DropOldest),
+            "drop-newest" => Some(SlowConsumerPolicy::DropNewest),
+            "disconnect" => Some(SlowConsumerPolicy::Disconnect),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SlowConsumerPolicy::DropOldest => "drop-oldest",
+            SlowConsumerPolicy::DropNewest => "drop-newest",
+            SlowConsumerPolicy::Disconnect => "disconnect"
+        }
+    }
+}
+
+/// What to do with control characters and invisible Unicode in messages relayed to other users.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ControlCharPolicy {
+    /// Remove them
+    Strip,
+
+    /// Write them out as something visible, like `\x1b`
+    Escape
+}
+
+impl ControlCharPolicy {
+    fn parse(name: &str) -> Option<ControlCharPolicy> {
+        match name {
+            "strip" => Some(ControlCharPolicy::Strip),
+            "escape" => Some(ControlCharPolicy::Escape),
+            _ => None
+        }
+    }
+}
+
+/// Numeric limits on what connections and users can do, the `[limits]` table of the config file.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Most client connections the server will hold at once
+    pub max_connections: usize,
+
+    /// Most client connections from a single address at once, 0 for no limit
+    pub max_connections_per_ip: usize,
+
+    /// Longest username in characters that can be picked
+    pub max_username_length: usize,
+
+    /// Number of failed reads or writes in a row before a connection is closed
+    pub max_failed_attempts: u32,
+
+    /// How long a dropped session is held for the user to /resume, in seconds
+    pub resume_grace_period: i64,
+
+    /// The most messages kept for a dropped session to replay on /resume
+    pub max_missed_messages: usize,
+
+    /// How many of a room's most recent messages are sent to a user when they join it
+    pub join_backlog: usize,
+
+    /// How many messages /history sends when it isn't given a count
+    pub history_lines: usize,
+
+    /// How many of each room's most recent messages are kept in the history file
+    pub max_history_per_room: usize,
+
+    /// How many rooms /rooms lists per page
+    pub rooms_per_page: usize,
+
+    /// The most rooms a single user can be in at once
+    pub max_rooms_per_user: usize,
+
+    /// Seconds a room that isn't persistent is kept after its last member leaves
+    pub empty_room_grace_period: i64,
+
+    /// The most messages waiting to be written to a single connection
+    pub max_queued_messages: usize,
+
+    /// The most bytes waiting to be written to a single connection
+    pub max_queued_bytes: usize,
+
+    /// The longest line a client can send, longer lines are discarded
+    pub max_line_length: usize,
+
+    /// The most unprocessed input held for a single connection before it is closed
+    pub max_read_buffer: usize,
+
+    /// Seconds a client has to finish a line it started before it is disconnected, 0 to never time out
+    pub partial_line_timeout: u64,
+
+    /// Seconds a connection can go without sending anything before it is disconnected, 0 to never disconnect
+    pub idle_timeout: u64,
+
+    /// Seconds a connection can go without sending anything before it is sent a PING, 0 to never send one
+    pub keepalive_interval: u64,
+
+    /// Seconds a user can go without sending anything before they are shown as away, 0 to never
+    pub auto_away_after: u64,
+
+    /// Seconds a new connection has to pick a username or log in before it is disconnected, 0 to wait forever
+    pub login_timeout: u64,
+
+    /// Lines a single connection can send in a burst before messages_per_minute kicks in
+    pub message_burst: u32,
+
+    /// Lines a single connection can keep sending per minute, 0 for no limit
+    pub messages_per_minute: u32,
+
+    /// Lines all the connections from one address can send in a burst before ip_messages_per_minute kicks in
+    pub ip_message_burst: u32,
+
+    /// Lines all the connections from one address can keep sending per minute, 0 for no limit
+    pub ip_messages_per_minute: u32,
+
+    /// Lines over the rate limits before a connection is muted, 0 to never mute
+    pub flood_mute_after: u32,
+
+    /// Seconds a connection stays muted for
+    pub flood_mute_seconds: u64,
+
+    /// Lines over the rate limits before a connection is disconnected, 0 to never disconnect
+    pub flood_disconnect_after: u32,
+
+    /// Failed logins from one address before it has to wait failed_login_lockout, 0 for no limit
+    pub max_failed_logins: u32,
+
+    /// Seconds an address that has failed to log in too often has to wait since its last failure
+    pub failed_login_lockout: u64
+}
+
+/// Everything that can be set from the config file or the command line.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Addresses the server accepts connections on
+    pub listen_addresses: Vec<SocketAddr>,
+
+    /// The room users are put in when they first pick a username
+    pub default_room: Roomname,
+
+    /// Rooms that are created when the server starts, in addition to the default room. These and the
+    /// default room are persistent, they stay with their topic when everyone leaves
+    pub initial_rooms: Vec<Roomname>,
+
+    /// Usernames nobody can pick, along with anything that looks like them
+    pub reserved_names: Vec<String>,
+
+    /// Let usernames use letters and numbers outside of ASCII. Look-alikes are only caught for the
+    /// common ones, see `username_key`
+    pub allow_unicode_names: bool,
+
+    /// Registered accounts that are server operators once they log in
+    pub operators: Vec<String>,
+
+    /// Operators => the hash of their password, the `[operator_passwords]` table of the config file.
+    /// Operator accounts that don't exist yet are created with these at startup, since nobody can
+    /// /register an operator's name. Hashes are made with --hash-password
+    pub operator_passwords: Vec<(String, String)>,
+
+    /// strftime format used for the timestamp in front of every message
+    pub timestamp_format: String,
+
+    pub log_level: LogLevel,
+
+    /// What to do when a connection's send queue goes over its limits
+    pub slow_consumer_policy: SlowConsumerPolicy,
+
+    /// What to do with control characters in messages, see `Sanitizer`
+    pub control_characters: ControlCharPolicy,
+
+    /// Let escape sequences that only set basic colors and styles through in messages
+    pub allow_colors: bool,
+
+    /// File that password protected accounts are stored in
+    pub accounts_file: String,
+
+    /// File that the message history of every room is stored in
+    pub history_file: String,
+
+    /// File that room bans are stored in
+    pub bans_file: String,
+
+    /// File that persistent rooms and their topics, modes and roles are stored in
+    pub rooms_file: String,
+
+    /// Addresses that may or may not connect, the `[access]` table of the config file
+    pub access: AccessList,
+
+    pub limits: Limits
+}
+
+pub enum ConfigError {
+    /// --help was passed, holds the usage text
+    Help(String),
+
+    /// --hash-password was passed, a password should be read from standard input and its hash printed
+    HashPassword,
+
+    /// The config file or command line is invalid, holds a description of what is wrong
+    Invalid(String)
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen_addresses: vec!["0.0.0.0:6567".parse().unwrap()],
+            default_room: "default".to_string(),
+            initial_rooms: Vec::new(),
+            reserved_names: vec!["server".to_string(), "admin".to_string(), "root".to_string(), "system".to_string()],
+            allow_unicode_names: false,
+            operators: Vec::new(),
+            operator_passwords: Vec::new(),
+            timestamp_format: "%Y:%m:%d %H:%M:%S".to_string(),
+            log_level: LogLevel::Info,
+            slow_consumer_policy: SlowConsumerPolicy::DropNewest,
+            control_characters: ControlCharPolicy::Strip,
+            allow_colors: false,
+            accounts_file: "accounts.txt".to_string(),
+            history_file: "history.log".to_string(),
+            bans_file: "bans.txt".to_string(),
+            rooms_file: "rooms.txt".to_string(),
+            access: AccessList::default(),
+            limits: Limits {
+                max_connections: 1024,
+                max_connections_per_ip: 16,
+                max_username_length: 24,
+                max_failed_attempts: 3,
+                resume_grace_period: 300,
+                max_missed_messages: 200,
+                join_backlog: 10,
+                history_lines: 20,
+                max_history_per_room: 1000,
+                rooms_per_page: 20,
+                max_rooms_per_user: 10,
+                empty_room_grace_period: 300,
+                max_queued_messages: 1000,
+                max_queued_bytes: 1024 * 1024,
+                max_line_length: 4096,
+                max_read_buffer: 64 * 1024,
+                partial_line_timeout: 30,
+                idle_timeout: 0,
+                auto_away_after: 900,
+                keepalive_interval: 0,
+                login_timeout: 60,
+                message_burst: 10,
+                messages_per_minute: 60,
+                ip_message_burst: 30,
+                ip_messages_per_minute: 300,
+                flood_mute_after: 3,
+                flood_mute_seconds: 60,
+                flood_disconnect_after: 10,
+                max_failed_logins: 5,
+                failed_login_lockout: 300
+            }
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Build the config from the command line arguments, including the program name. Settings from the
+    /// command line take precedence over the config file.
+    pub fn from_args(args: &[String]) -> Result<ServerConfig, ConfigError> {
+        let mut opts = Options::new();
+        opts.optopt("c", "config", "read settings from a TOML config file", "FILE");
+        opts.optmulti("l", "listen", "address to accept connections on, may be given more than once", "ADDR");
+        opts.optopt("", "max-connections", "most client connections to hold at once", "N");
+        opts.optopt("", "default-room", "room users are put in when they pick a username", "ROOM");
+        opts.optmulti("", "room", "room to create at startup, may be given more than once", "ROOM");
+        opts.optopt("", "timestamp-format", "strftime format for message timestamps", "FORMAT");
+        opts.optopt("", "log-level", "one of error, warn, info or debug", "LEVEL");
+        opts.optopt("", "slow-consumer-policy", "one of drop-oldest, drop-newest or disconnect", "POLICY");
+        opts.optflag("", "hash-password", "read a password from standard input, print its hash for operator_passwords and exit");
+        opts.optflag("h", "help", "print this help and exit");
+
+        let usage = opts.usage(&format!("Usage: {} [options]", args.get(0).map(|s| s.as_str()).unwrap_or("simple_chat")));
+
+        let matches = match opts.parse(&args[1..]) {
+            Ok(matches) => matches,
+            Err(e) => {
+                return Err(ConfigError::Invalid(format!("{}\n\n{}", e, usage)));
+            }
+        };
+
+        if matches.opt_present("help") {
+            return Err(ConfigError::Help(usage));
+        }
+
+        if matches.opt_present("hash-password") {
+            return Err(ConfigError::HashPassword);
+        }
+
+        let mut config = ServerConfig::default();
+
+        if let Some(path) = matches.opt_str("config") {
+            if let Err(e) = config.apply_file(&path) {
+                return Err(ConfigError::Invalid(format!("Invalid config file {}: {}", path, e)));
+            }
+        }
+
+        if let Err(e) = config.apply_matches(&matches) {
+            return Err(ConfigError::Invalid(format!("Invalid command line option: {}", e)));
+        }
+
+        match config.validate() {
+            Ok(_) => Ok(config),
+            Err(e) => Err(ConfigError::Invalid(format!("Invalid configuration: {}", e)))
+        }
+    }
+
+    fn apply_file(&mut self, path: &str) -> Result<(), String> {
+        let mut contents = String::new();
+        if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut contents)) {
+            return Err(format!("{}", e));
+        }
+
+        let mut parser = toml::Parser::new(&contents);
+        match parser.parse() {
+            Some(table) => self.apply_table(&table),
+            None => {
+                let errors: Vec<String> = parser.errors.iter().map(|e| {
+                    let (line, col) = parser.to_linecol(e.lo);
+                    format!("line {} column {}: {}", line + 1, col + 1, e.desc)
+                }).collect();
+                Err(errors.join(", "))
+            }
+        }
+    }
+
+    fn apply_table(&mut self, table: &toml::Table) -> Result<(), String> {
+        for (key, value) in table.iter() {
+            match key.as_str() {
+                "listen" => {
+                    self.listen_addresses = try!(parse_addresses(&try!(get_string_list(value, key))));
+                },
+                "timestamp_format" => {
+                    self.timestamp_format = try!(get_string(value, key));
+                },
+                "log_level" => {
+                    self.log_level = try!(parse_log_level(&try!(get_string(value, key))));
+                },
+                "slow_consumer_policy" => {
+                    self.slow_consumer_policy = try!(parse_slow_consumer_policy(&try!(get_string(value, key))));
+                },
+                "accounts_file" => {
+                    self.accounts_file = try!(get_string(value, key));
+                },
+                "control_characters" => {
+                    let name = try!(get_string(value, key));
+                    self.control_characters = match ControlCharPolicy::parse(&name) {
+                        Some(policy) => policy,
+                        None => {
+                            return Err(format!("`{}` is not a valid control character policy, expected strip or escape", name));
+                        }
+                    };
+                },
+                "allow_colors" => {
+                    self.allow_colors = try!(get_bool(value, key));
+                },
+                "reserved_names" => {
+                    self.reserved_names = try!(get_string_list(value, key));
+                },
+                "allow_unicode_names" => {
+                    self.allow_unicode_names = try!(get_bool(value, key));
+                },
+                "operators" => {
+                    self.operators = try!(get_string_list(value, key));
+                },
+                "operator_passwords" => {
+                    try!(self.apply_operator_passwords_table(try!(get_table(value, key))));
+                },
+                "history_file" => {
+                    self.history_file = try!(get_string(value, key));
+                },
+                "bans_file" => {
+                    self.bans_file = try!(get_string(value, key));
+                },
+                "rooms_file" => {
+                    self.rooms_file = try!(get_string(value, key));
+                },
+                "rooms" => {
+                    try!(self.apply_rooms_table(try!(get_table(value, key))));
+                },
+                "limits" => {
+                    try!(self.apply_limits_table(try!(get_table(value, key))));
+                },
+                "access" => {
+                    try!(self.apply_access_table(try!(get_table(value, key))));
+                },
+                _ => {
+                    return Err(format!("unknown setting `{}`", key));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_rooms_table(&mut self, table: &toml::Table) -> Result<(), String> {
+        for (key, value) in table.iter() {
+            let name = format!("rooms.{}", key);
+            match key.as_str() {
+                "default" => {
+                    self.default_room = try!(get_string(value, &name));
+                },
+                "initial" => {
+                    self.initial_rooms = try!(get_string_list(value, &name));
+                },
+                _ => {
+                    return Err(format!("unknown setting `{}`", name));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_operator_passwords_table(&mut self, table: &toml::Table) -> Result<(), String> {
+        for (key, value) in table.iter() {
+            let hash = try!(get_string(value, &format!("operator_passwords.{}", key)));
+            self.operator_passwords.push((key.clone(), hash));
+        }
+
+        Ok(())
+    }
+
+    fn apply_access_table(&mut self, table: &toml::Table) -> Result<(), String> {
+        for (key, value) in table.iter() {
+            let name = format!("access.{}", key);
+            match key.as_str() {
+                "allow" => {
+                    self.access.allow = try!(parse_ranges(&try!(get_string_list(value, &name))));
+                },
+                "deny" => {
+                    self.access.deny = try!(parse_ranges(&try!(get_string_list(value, &name))));
+                },
+                _ => {
+                    return Err(format!("unknown setting `{}`", name));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_limits_table(&mut self, table: &toml::Table) -> Result<(), String> {
+        for (key, value) in table.iter() {
+            let name = format!("limits.{}", key);
+            let count = try!(get_count(value, &name));
+            match key.as_str() {
+                "max_connections" => {
+                    self.limits.max_connections = count as usize;
+                },
+                "max_connections_per_ip" => {
+                    self.limits.max_connections_per_ip = count as usize;
+                },
+                "max_username_length" => {
+                    self.limits.max_username_length = count as usize;
+                },
+                "max_failed_attempts" => {
+                    self.limits.max_failed_attempts = count as u32;
+                },
+                "resume_grace_period" => {
+                    self.limits.resume_grace_period = count as i64;
+                },
+                "max_missed_messages" => {
+                    self.limits.max_missed_messages = count as usize;
+                },
+                "join_backlog" => {
+                    self.limits.join_backlog = count as usize;
+                },
+                "history_lines" => {
+                    self.limits.history_lines = count as usize;
+                },
+                "max_history_per_room" => {
+                    self.limits.max_history_per_room = count as usize;
+                },
+                "rooms_per_page" => {
+                    self.limits.rooms_per_page = count as usize;
+                },
+                "max_rooms_per_user" => {
+                    self.limits.max_rooms_per_user = count as usize;
+                },
+                "empty_room_grace_period" => {
+                    self.limits.empty_room_grace_period = count as i64;
+                },
+                "max_queued_messages" => {
+                    self.limits.max_queued_messages = count as usize;
+                },
+                "max_queued_bytes" => {
+                    self.limits.max_queued_bytes = count as usize;
+                },
+                "max_line_length" => {
+                    self.limits.max_line_length = count as usize;
+                },
+                "max_read_buffer" => {
+                    self.limits.max_read_buffer = count as usize;
+                },
+                "partial_line_timeout" => {
+                    self.limits.partial_line_timeout = count as u64;
+                },
+                "idle_timeout" => {
+                    self.limits.idle_timeout = count as u64;
+                },
+                "auto_away_after" => {
+                    self.limits.auto_away_after = count as u64;
+                },
+                "keepalive_interval" => {
+                    self.limits.keepalive_interval = count as u64;
+                },
+                "login_timeout" => {
+                    self.limits.login_timeout = count as u64;
+                },
+                "message_burst" => {
+                    self.limits.message_burst = count;
+                },
+                "messages_per_minute" => {
+                    self.limits.messages_per_minute = count;
+                },
+                "ip_message_burst" => {
+                    self.limits.ip_message_burst = count;
+                },
+                "ip_messages_per_minute" => {
+                    self.limits.ip_messages_per_minute = count;
+                },
+                "flood_mute_after" => {
+                    self.limits.flood_mute_after = count;
+                },
+                "flood_mute_seconds" => {
+                    self.limits.flood_mute_seconds = count as u64;
+                },
+                "flood_disconnect_after" => {
+                    self.limits.flood_disconnect_after = count;
+                },
+                "max_failed_logins" => {
+                    self.limits.max_failed_logins = count;
+                },
+                "failed_login_lockout" => {
+                    self.limits.failed_login_lockout = count as u64;
+                },
+                _ => {
+                    return Err(format!("unknown setting `{}`", name));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply_matches(&mut self, matches: &::getopts::Matches) -> Result<(), String> {
+        let listen = matches.opt_strs("listen");
+        if !listen.is_empty() {
+            self.listen_addresses = try!(parse_addresses(&listen));
+        }
+
+        if let Some(max_connections) = matches.opt_str("max-connections") {
+            match max_connections.parse() {
+                Ok(max_connections) => {
+                    self.limits.max_connections = max_connections;
+                },
+                Err(_) => {
+                    return Err(format!("--max-connections must be a number, got `{}`", max_connections));
+                }
+            }
+        }
+
+        if let Some(default_room) = matches.opt_str("default-room") {
+            self.default_room = default_room;
+        }
+
+        let rooms = matches.opt_strs("room");
+        if !rooms.is_empty() {
+            self.initial_rooms = rooms;
+        }
+
+        if let Some(timestamp_format) = matches.opt_str("timestamp-format") {
+            self.timestamp_format = timestamp_format;
+        }
+
+        if let Some(log_level) = matches.opt_str("log-level") {
+            self.log_level = try!(parse_log_level(&log_level));
+        }
+
+        if let Some(policy) = matches.opt_str("slow-consumer-policy") {
+            self.slow_consumer_policy = try!(parse_slow_consumer_policy(&policy));
+        }
+
+        Ok(())
+    }
+
+    /// Check the settings that can't be checked while they are being read.
+    fn validate(&self) -> Result<(), String> {
+        if self.listen_addresses.is_empty() {
+            return Err("at least one listen address is required".into());
+        }
+
+        if self.limits.max_connections == 0 {
+            return Err("max_connections must be at least 1".into());
+        }
+
+        if self.limits.max_queued_messages == 0 || self.limits.max_queued_bytes == 0 {
+            return Err("max_queued_messages and max_queued_bytes must be at least 1".into());
+        }
+
+        if self.limits.max_history_per_room == 0 {
+            return Err("max_history_per_room must be at least 1".into());
+        }
+
+        if self.limits.rooms_per_page == 0 {
+            return Err("rooms_per_page must be at least 1".into());
+        }
+
+        if self.limits.max_rooms_per_user == 0 {
+            return Err("max_rooms_per_user must be at least 1".into());
+        }
+
+        if self.limits.max_username_length == 0 {
+            return Err("max_username_length must be at least 1".into());
+        }
+
+        if self.limits.max_line_length == 0 {
+            return Err("max_line_length must be at least 1".into());
+        }
+
+        // A line is only discarded once more than max_line_length bytes of it have been buffered
+        if self.limits.max_read_buffer <= self.limits.max_line_length {
+            return Err("max_read_buffer must be larger than max_line_length".into());
+        }
+
+        // Otherwise quiet clients are disconnected before they get a PING they could answer
+        let limits = &self.limits;
+        if limits.idle_timeout > 0 && limits.keepalive_interval > 0 && limits.keepalive_interval >= limits.idle_timeout {
+            return Err("keepalive_interval must be shorter than idle_timeout".into());
+        }
+
+        if limits.message_burst == 0 || limits.ip_message_burst == 0 {
+            return Err("message_burst and ip_message_burst must be at least 1".into());
+        }
+
+        for &(ref name, ref hash) in self.operator_passwords.iter() {
+            if !self.operators.contains(name) {
+                return Err(format!("operator_passwords has a password for {}, who isn't listed in operators", name));
+            }
+            if !hash.starts_with("$rscrypt$") {
+                return Err(format!("The password for operator {} must be a hash made with --hash-password", name));
+            }
+        }
+
+        try!(validate_room_name(&self.default_room));
+        for room_name in self.initial_rooms.iter() {
+            try!(validate_room_name(room_name));
+        }
+
+        if time::strftime(&self.timestamp_format, &time::now()).is_err() {
+            return Err(format!("`{}` is not a valid timestamp format", self.timestamp_format));
+        }
+
+        Ok(())
+    }
+}
+
+/// Room names come from the first word after /join, so they can't be empty or contain whitespace.
+fn validate_room_name(room_name: &str) -> Result<(), String> {
+    if room_name.is_empty() || room_name.contains(char::is_whitespace) {
+        return Err(format!("`{}` is not a valid room name", room_name));
+    }
+    Ok(())
+}
+
+fn parse_addresses(addresses: &[String]) -> Result<Vec<SocketAddr>, String> {
+    let mut parsed = Vec::new();
+    for address in addresses.iter() {
+        match address.parse() {
+            Ok(address) => {
+                parsed.push(address);
+            },
+            Err(_) => {
+                return Err(format!("`{}` is not a valid address, expected IP:PORT", address));
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+fn parse_ranges(ranges: &[String]) -> Result<Vec<IpRange>, String> {
+    let mut parsed = Vec::new();
+    for range in ranges.iter() {
+        parsed.push(try!(IpRange::parse(range)));
+    }
+    Ok(parsed)
+}
+
+fn parse_log_level(name: &str) -> Result<LogLevel, String> {
+    match LogLevel::parse(name) {
+        Some(level) => Ok(level),
+        None => Err(format!("`{}` is not a valid log level, expected error, warn, info or debug", name))
+    }
+}
+
+fn parse_slow_consumer_policy(name: &str) -> Result<SlowConsumerPolicy, String> {
+    match SlowConsumerPolicy::parse(name) {
+        Some(policy) => Ok(policy),
+        None => Err(format!("`{}` is not a valid slow consumer policy, expected drop-oldest, drop-newest or disconnect", name))
+    }
+}
+
+fn get_string(value: &toml::Value, name: &str) -> Result<String, String> {
+    match value.as_str() {
+        Some(s) => Ok(s.to_string()),
+        None => Err(format!("`{}` must be a string, got {}", name, value.type_str()))
+    }
+}
+
+fn get_bool(value: &toml::Value, name: &str) -> Result<bool, String> {
+    match value.as_bool() {
+        Some(b) => Ok(b),
+        None => Err(format!("`{}` must be true or false, got {}", name, value.type_str()))
+    }
+}
+
+fn get_count(value: &toml::Value, name: &str) -> Result<u32, String> {
+    match value.as_integer() {
+        Some(n) if n >= 0 && n <= ::std::u32::MAX as i64 => Ok(n as u32),
+        Some(n) => Err(format!("`{}` must be between 0 and {}, got {}", name, ::std::u32::MAX, n)),
+        None => Err(format!("`{}` must be an integer, got {}", name, value.type_str()))
+    }
+}
+
+fn get_string_list(value: &toml::Value, name: &str) -> Result<Vec<String>, String> {
+    match value.as_slice() {
+        Some(values) => {
+            let mut strings = Vec::new();
+            for value in values.iter() {
+                strings.push(try!(get_string(value, name)));
+            }
+            Ok(strings)
+        },
+        None => Err(format!("`{}` must be an array of strings, got {}", name, value.type_str()))
+    }
+}
+
+fn get_table<'a>(value: &'a toml::Value, name: &str) -> Result<&'a toml::Table, String> {
+    match value.as_table() {
+        Some(table) => Ok(table),
+        None => Err(format!("`{}` must be a table, got {}", name, value.type_str()))
+    }
+}
@@ -1,14 +1,16 @@
 use std::mem;
 use std::collections::vec_deque::VecDeque;
 use std::io;
-use std::io::Cursor;
 use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::rc::Rc;
 
 use mio;
 use mio::{Token, EventLoop, EventSet, TryRead, TryWrite, PollOpt};
 use mio::tcp::{TcpStream};
+use time::{self, Timespec};
 
+use super::config::{Limits, LogLevel, ServerConfig, SlowConsumerPolicy};
 use super::server::ChatServer;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -17,6 +19,27 @@ pub enum ChatConnectionState {
     Closed
 }
 
+/// Counts of how often a connection's send queue limits kicked in, see `SlowConsumerPolicy`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OverflowCounts {
+    /// Queued messages dropped to make room for new ones
+    pub dropped_oldest: u64,
+
+    /// New messages dropped because the queue was full
+    pub dropped_newest: u64,
+
+    /// Connections closed because their queue was full
+    pub disconnects: u64
+}
+
+impl OverflowCounts {
+    pub fn add(&mut self, other: &OverflowCounts) {
+        self.dropped_oldest += other.dropped_oldest;
+        self.dropped_newest += other.dropped_newest;
+        self.disconnects += other.disconnects;
+    }
+}
+
 /// Represents a single connection to the chat server.
 pub struct ChatConnection {
     /// The TCP socket
@@ -30,8 +53,8 @@ pub struct ChatConnection {
 
     /// Buffer of bytes read from this connection. 
     ///
-    /// Is cleared every time a newline character is received and the
-    /// contents will be queued up to be written to all the other connections
+    /// Each complete line is taken out of the front of the buffer by `next_line`, so it only
+    /// holds the start of a line that hasn't finished arriving yet
     read_buf: Vec<u8>,
 
     /// A queue of reference counted references to bytebuffers
@@ -40,18 +63,45 @@ pub struct ChatConnection {
     /// the next time it becomes ready to be written to
     send_queue: VecDeque<Rc<Vec<u8>>>,
 
+    /// How many bytes of the message at the front of the send_queue have already been written
+    write_offset: usize,
+
+    /// Set while the rest of a line that was too long is being thrown away, up to its newline
+    discarding_line: bool,
+
+    /// The address the client connected from, if the socket could tell us
+    peer_ip: Option<IpAddr>,
+
+    /// When anything was last read from the socket, used to find connections that have gone quiet
+    last_read: Timespec,
+
+    /// Total size of the messages in the send_queue
+    queued_bytes: usize,
+
+    /// Messages dropped because the queue was full that the client hasn't been told about yet
+    skipped_messages: u64,
+
+    /// How often the send queue limits have kicked in for this connection
+    overflow_counts: OverflowCounts,
+
     /// Is this connection open/closed
     state: ChatConnectionState,
 
-    /// Number of failed read attempts on the socket, currently abort after 3
+    /// Number of failed read attempts on the socket, abort after limits.max_failed_attempts
     failed_read_attempts: u32,
 
-    /// Number of failed write attempts on the socket, currently abort after 3
-    failed_write_attempts: u32
+    /// Number of failed write attempts on the socket, abort after limits.max_failed_attempts
+    failed_write_attempts: u32,
+
+    limits: Limits,
+
+    /// What to do when the send_queue goes over its limits
+    slow_consumer_policy: SlowConsumerPolicy
 }
 
 impl ChatConnection {
-    pub fn new(socket: TcpStream, token: mio::Token) -> ChatConnection {
+    pub fn new(socket: TcpStream, token: mio::Token, config: &ServerConfig) -> ChatConnection {
+        let peer_ip = socket.peer_addr().ok().map(|addr| addr.ip());
         ChatConnection {
             socket: socket,
             token: token,
@@ -59,21 +109,26 @@ impl ChatConnection {
             // Should be done with_capacity for a reasonable message size
             read_buf: Vec::new(),
             send_queue: VecDeque::new(),
+            write_offset: 0,
+            discarding_line: false,
+            peer_ip: peer_ip,
+            last_read: time::get_time(),
+            queued_bytes: 0,
+            skipped_messages: 0,
+            overflow_counts: OverflowCounts::default(),
             state: ChatConnectionState::Open,
             failed_read_attempts: 0,
-            failed_write_attempts: 0
+            failed_write_attempts: 0,
+            limits: config.limits,
+            slow_consumer_policy: config.slow_consumer_policy
         }
     }
 
-    /// Returns Some if the read_buf is ready to be written to the other connections
-    /// Otherwise, return none and continue reading into the read_buf until ready 
+    /// Reads whatever is available on the socket into the read_buf, returning the number of bytes read.
     ///
-    /// Clears the read buff when it returns Some
-    ///
-    /// Returns an RC (Thread-local reference-counted box) so that we can just copy a reference to each
-    /// connections send_queue, and once they have all been written to, the reference count should drop
-    /// to 0 and they the vec should automatically be freed.
-    pub fn read(&mut self) -> io::Result<Option<String>> {
+    /// Complete lines are taken back out of the read_buf with `next_line`, anything after the last
+    /// newline is kept until the rest of its line arrives.
+    pub fn read(&mut self) -> io::Result<usize> {
         match self.socket.try_read_buf(&mut self.read_buf) {
             // 0 Bytes were read
             Ok(Some(0)) => {
@@ -85,46 +140,35 @@ impl ChatConnection {
             Ok(Some(n)) => {
                 super::log_something(format!("read {} bytes", n));
                 self.failed_read_attempts = 0;
+                self.last_read = time::get_time();
 
-                // The conditions have been met so that the input read from this connection
-                // is now ready to be written to the other clients
-                //
-                // Limit is the number of characters up to the newline was detected, all characters after the newline are discarded.
-                if let Some(limit) = self.is_ready_to_write() {
-
-                    // Clear the current read buffer, but keep a handle to it since we will be returning it
-                    // so that the server can add it to the other connection's send_queue's
-                    let read_buf = mem::replace(&mut self.read_buf, Vec::new());
-
-                    self.read_buf.truncate(limit);
-                    return match String::from_utf8(read_buf) {
-                        Ok(message) => {
-                            return Ok(Some(message));
-                        },
-                        Err(_) => {
-                            return Err(::std::io::Error::new(ErrorKind::InvalidInput, "Invalid utf8"));
-                        }
-                    }
-                } else {
-                    return Ok(None);
+                // Lines are taken out of the read_buf as soon as they are complete, so this only fills up
+                // if the client sends far more than it could have meant to
+                if self.read_buf.len() > self.limits.max_read_buffer {
+                    super::log_at(LogLevel::Info, format!("Closing {:?}, it sent more than {} bytes of input at once", self.token, self.limits.max_read_buffer));
+                    self.read_buf.clear();
+                    self.state = ChatConnectionState::Closed;
+                    return Err(::std::io::Error::new(ErrorKind::Other, "Read buffer full"));
                 }
+
+                return Ok(n);
             }
             // The socket's a liar! It wasn't actually ready for us to read from. 
             // Nothing we need to do here. Just keep listening same as before.
             Ok(None) => {
                 self.failed_read_attempts = 0;
 
-                return Ok(None);
+                return Ok(0);
             }
             Err(e) => {
                 match e {
                     // Todo, determine what error kinds warrant retries, immediately closing the connection, ect...
                     // https://doc.rust-lang.org/std/io/enum.ErrorKind.html
                     // 
-                    // For now just close the connection after 3 failed reads from the socket, regardless of the error type.
+                    // For now just close the connection after too many failed reads from the socket, regardless of the error type.
                     _ => {
                         self.failed_read_attempts += 1;
-                        if self.failed_read_attempts > 3 {
+                        if self.failed_read_attempts > self.limits.max_failed_attempts {
                             self.state = ChatConnectionState::Closed;
                         }
                     }
@@ -134,47 +178,53 @@ impl ChatConnection {
         }
     }
 
-    /// Writes to the connection, using the next entry from the send_queue.
-    /// 
-    /// Only the next entry in the send_queue will be sent per call. It may be better to just send 
-    /// them all at once, separated by newlines.
+    /// Writes as much of the send_queue to the connection as the socket will accept.
+    ///
+    /// Messages are written straight out of the shared buffers in the queue, so a message sent to a whole
+    /// room is never copied per connection. A short write leaves the rest of the message at the front of
+    /// the queue, with write_offset marking how much of it has already gone out.
     pub fn write(&mut self) -> io::Result<()> {
-        let res = match self.send_queue.pop_front() {
-            Some(buf) => {
-                match self.socket.try_write_buf(&mut Cursor::new(buf.to_vec())) {
-                    Ok(None) => {
-                        super::log_something(format!("client flushing buf; WouldBlock"));
-
-                        // Put message back into the queue so we can try again
-                        self.failed_write_attempts += 1;
-                        if self.failed_write_attempts > 3 {
-                            self.state = ChatConnectionState::Closed;
-                            Err(io::Error::new(io::ErrorKind::Other, "Exceeded failed write attempts limit."))
-                        } else {
-                            self.send_queue.push_front(buf);
-                            Ok(())
-                        }
-                    },
-                    Ok(Some(n)) => {
-                        self.failed_write_attempts = 0;
-                        super::log_something(format!("CONN : we wrote {} bytes", n));
-                        Ok(())
-                    },
-                    Err(e) => {
-                        super::log_something(format!("Failed to send buffer for {:?}, error: {}", self.token, e));
-                        self.failed_write_attempts += 1;
-                        if self.failed_write_attempts > 3 {
-                            self.state = ChatConnectionState::Closed;
-                        }
+        let mut res = Ok(());
+
+        loop {
+            let result = match self.send_queue.front() {
+                Some(buf) => self.socket.try_write(&buf[self.write_offset..]),
+                None => {
+                    break;
+                }
+            };
+
+            match result {
+                // The socket can't take any more right now, the rest is sent on the next writable event
+                Ok(None) | Ok(Some(0)) => {
+                    super::log_something(format!("client flushing buf; WouldBlock"));
+                    break;
+                },
+                Ok(Some(n)) => {
+                    self.failed_write_attempts = 0;
+                    super::log_something(format!("CONN : we wrote {} bytes", n));
 
-                        Err(e)
+                    self.write_offset += n;
+                    if self.write_offset >= self.send_queue.front().map(|buf| buf.len()).unwrap_or(0) {
+                        self.pop_message();
+                        self.write_offset = 0;
                     }
+                },
+                Err(e) => {
+                    super::log_something(format!("Failed to send buffer for {:?}, error: {}", self.token, e));
+                    self.failed_write_attempts += 1;
+                    if self.failed_write_attempts > self.limits.max_failed_attempts {
+                        self.state = ChatConnectionState::Closed;
+                    }
+
+                    res = Err(e);
+                    break;
                 }
             }
-            None => {
-                Err(io::Error::new(io::ErrorKind::Other, "Could not pop send queue"))
-            }
-        };
+        }
+
+        // Now that the client has caught up, tell them what they missed
+        self.queue_skipped_notice();
 
         // If that was the last message in this connections send queue, 
         // then we don't need to listen for writes until another message gets added.
@@ -191,11 +241,101 @@ impl ChatConnection {
 
     /// Queues a message up to be written to this connection the next time it recieves a call to write
     /// If this connection was not subscribed to write events before, it is now.
+    ///
+    /// If the send_queue is full the slow consumer policy decides what happens. Under the disconnect
+    /// policy the connection is closed, so callers need to check `is_closed` afterwards.
     pub fn send_message(&mut self, message: Rc<Vec<u8>>) {
+        if self.is_closed() {
+            return;
+        }
+
+        if !self.has_room_for(message.len()) {
+            match self.slow_consumer_policy {
+                SlowConsumerPolicy::DropOldest => {
+                    while !self.has_room_for(message.len()) && self.drop_oldest() {}
+
+                    // Only the partly written message is left, and it can't be dropped
+                    if !self.has_room_for(message.len()) {
+                        self.skip_message();
+                        return;
+                    }
+                },
+                SlowConsumerPolicy::DropNewest => {
+                    self.skip_message();
+                    return;
+                },
+                SlowConsumerPolicy::Disconnect => {
+                    super::log_at(LogLevel::Info, format!("Closing {:?}, its send queue is full", self.token));
+                    self.overflow_counts.disconnects += 1;
+                    self.send_queue.clear();
+                    self.queued_bytes = 0;
+                    self.write_offset = 0;
+                    self.state = ChatConnectionState::Closed;
+                    return;
+                }
+            }
+        }
+
+        self.queue_skipped_notice();
+        self.push_message(message);
+    }
+
+    pub fn overflow_counts(&self) -> &OverflowCounts {
+        &self.overflow_counts
+    }
+
+    /// Is there room in the send_queue for another message of the given size. A message always fits
+    /// in an empty queue, otherwise one bigger than the byte limit could never be sent.
+    fn has_room_for(&self, len: usize) -> bool {
+        self.send_queue.is_empty() ||
+            (self.send_queue.len() < self.limits.max_queued_messages && self.queued_bytes + len <= self.limits.max_queued_bytes)
+    }
+
+    /// Drop the oldest message that hasn't started being written yet. Returns false if there wasn't one.
+    fn drop_oldest(&mut self) -> bool {
+        // Dropping a partly written message would leave half a line on the client's screen
+        let index = if self.write_offset > 0 { 1 } else { 0 };
+        match self.send_queue.remove(index) {
+            Some(message) => {
+                self.queued_bytes -= message.len();
+                self.overflow_counts.dropped_oldest += 1;
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Drop a new message because the queue is full, the client is told how many were skipped once it catches up.
+    fn skip_message(&mut self) {
+        self.skipped_messages += 1;
+        self.overflow_counts.dropped_newest += 1;
+    }
+
+    /// If new messages were dropped, queue up a line saying how many once there is room for it.
+    fn queue_skipped_notice(&mut self) {
+        if self.skipped_messages == 0 {
+            return;
+        }
+
+        let notice = format!("Server: {} message(s) were skipped because you weren't keeping up\n", self.skipped_messages).into_bytes();
+        if self.has_room_for(notice.len()) {
+            self.skipped_messages = 0;
+            self.push_message(Rc::new(notice));
+        }
+    }
+
+    fn push_message(&mut self, message: Rc<Vec<u8>>) {
+        self.queued_bytes += message.len();
         self.send_queue.push_back(message);
         self.interest.insert(EventSet::writable());
     }
 
+    fn pop_message(&mut self) {
+        if let Some(message) = self.send_queue.pop_front() {
+            self.queued_bytes -= message.len();
+        }
+    }
+
     // When we 
     pub fn register(&self, event_loop: &mut mio::EventLoop<ChatServer>) -> io::Result<()> {
         event_loop.register_opt(
@@ -223,19 +363,143 @@ impl ChatConnection {
         self.state = ChatConnectionState::Closed;
     }
 
-    /// Does this correctly handle mutlibyte utf8 characters currently? 
+    /// Takes the next complete line out of the read_buf, without its line ending. Both "\n" and the
+    /// "\r\n" sent by telnet are accepted. Returns None once there are no complete lines left.
     ///
-    /// If the connection is ready to write to the other connections, return Some with
-    /// the number of bytes to take from the read buffer to write to the other connections
-    /// Otherwise return None
-    fn is_ready_to_write(&self) -> Option<usize> {
-        return match self.read_buf.iter().position(|b| *b == b'\n') {
-            Some(pos) => {
-                Some(pos + 1)
-            },
+    /// UTF-8 is only checked once a line is complete, so multibyte characters split across reads are fine.
+    /// A line that isn't valid UTF-8 is discarded and returned as an InvalidInput error.
+    ///
+    /// A line longer than limits.max_line_length is returned as an InvalidInput error as soon as that
+    /// many bytes of it have arrived, and the rest of it is thrown away when it comes in.
+    pub fn next_line(&mut self) -> Option<io::Result<String>> {
+        take_line(&mut self.read_buf, &mut self.discarding_line, self.limits.max_line_length)
+    }
+
+    /// Is part of a line waiting in the read_buf for the rest of it to arrive
+    pub fn has_partial_line(&self) -> bool {
+        !self.read_buf.is_empty() || self.discarding_line
+    }
+
+    pub fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_ip
+    }
+
+    /// How long it has been since anything was read from the socket.
+    pub fn idle_time(&self) -> time::Duration {
+        time::get_time() - self.last_read
+    }
+}
+
+/// Does the work of `ChatConnection::next_line`, kept apart from the socket so it can be tested on its own.
+fn take_line(read_buf: &mut Vec<u8>, discarding_line: &mut bool, max_line_length: usize) -> Option<io::Result<String>> {
+    loop {
+        let pos = match read_buf.iter().position(|b| *b == b'\n') {
+            Some(pos) => pos,
             None => {
-                None
+                if read_buf.len() > max_line_length {
+                    // Don't wait for the newline, it might never come
+                    read_buf.clear();
+                    if !*discarding_line {
+                        *discarding_line = true;
+                        return Some(Err(line_too_long_error(max_line_length)));
+                    }
+                } else if *discarding_line {
+                    read_buf.clear();
+                }
+                return None;
             }
         };
+
+        // Keep everything after the newline for the following lines
+        let rest = read_buf.split_off(pos + 1);
+        let mut line = mem::replace(read_buf, rest);
+
+        // The start of this line was already reported as too long
+        if *discarding_line {
+            *discarding_line = false;
+            continue;
+        }
+
+        line.truncate(pos);
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        if line.len() > max_line_length {
+            return Some(Err(line_too_long_error(max_line_length)));
+        }
+
+        return match String::from_utf8(line) {
+            Ok(message) => Some(Ok(message)),
+            Err(_) => Some(Err(::std::io::Error::new(ErrorKind::InvalidInput, "Invalid utf8")))
+        };
+    }
+}
+
+fn line_too_long_error(max_line_length: usize) -> io::Error {
+    ::std::io::Error::new(ErrorKind::InvalidInput, format!("Lines can't be longer than {} bytes", max_line_length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::take_line;
+
+    /// Feeds `input` in as if it had been read from a socket, and returns every line that comes out,
+    /// with errors as "error".
+    fn lines(read_buf: &mut Vec<u8>, discarding_line: &mut bool, input: &[u8]) -> Vec<String> {
+        read_buf.extend(input.iter().cloned());
+        let mut lines = Vec::new();
+        while let Some(line) = take_line(read_buf, discarding_line, 10) {
+            lines.push(line.unwrap_or("error".to_string()));
+        }
+        lines
+    }
+
+    #[test]
+    fn complete_lines_come_out_without_their_endings() {
+        let (mut buf, mut discarding) = (Vec::new(), false);
+        assert_eq!(lines(&mut buf, &mut discarding, b"one\ntwo\r\n\nthree"), vec!["one", "two", ""]);
+        assert_eq!(buf, b"three".to_vec());
+    }
+
+    #[test]
+    fn partial_lines_wait_for_the_rest() {
+        let (mut buf, mut discarding) = (Vec::new(), false);
+        assert!(lines(&mut buf, &mut discarding, b"hel").is_empty());
+        assert_eq!(lines(&mut buf, &mut discarding, b"lo\r"), Vec::<String>::new());
+        assert_eq!(lines(&mut buf, &mut discarding, b"\n"), vec!["hello"]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn characters_split_across_reads_are_put_back_together() {
+        let (mut buf, mut discarding) = (Vec::new(), false);
+        let word = "zoë\n".as_bytes();
+        assert!(lines(&mut buf, &mut discarding, &word[..3]).is_empty());
+        assert_eq!(lines(&mut buf, &mut discarding, &word[3..]), vec!["zoë"]);
+    }
+
+    #[test]
+    fn invalid_utf8_is_an_error_for_that_line_only() {
+        let (mut buf, mut discarding) = (Vec::new(), false);
+        assert_eq!(lines(&mut buf, &mut discarding, b"a\xffb\nok\n"), vec!["error", "ok"]);
+    }
+
+    #[test]
+    fn long_lines_are_reported_once_and_thrown_away() {
+        let (mut buf, mut discarding) = (Vec::new(), false);
+
+        // Reported as soon as more than the limit has arrived, without waiting for the newline
+        assert_eq!(lines(&mut buf, &mut discarding, b"0123456789ab"), vec!["error"]);
+        assert!(discarding);
+        assert!(lines(&mut buf, &mut discarding, b"cdefghijklmnop").is_empty());
+        assert_eq!(lines(&mut buf, &mut discarding, b"qrs\nnext\n"), vec!["next"]);
+        assert!(!discarding);
+
+        // A long line that arrives all at once
+        assert_eq!(lines(&mut buf, &mut discarding, b"0123456789ab\nnext\n"), vec!["error", "next"]);
+
+        // Exactly at the limit is fine
+        assert_eq!(lines(&mut buf, &mut discarding, b"0123456789\r\n"), vec!["0123456789"]);
     }
 }
@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use mio::Token;
+use time::{self, Timespec};
+
+use super::config::Limits;
+
+/// Seconds without going over the limits before a connection's strikes are forgotten.
+const STRIKE_MEMORY_SECS: i64 = 60;
+
+/// What to do with a line a client sent, decided by `FloodControl::check`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FloodVerdict {
+    /// Handle the line as usual
+    Allow,
+
+    /// Discard the line and warn the client, it just went over the limits for the first time
+    Warn,
+
+    /// Discard the line without a reply, the client has already been warned or told it is muted
+    Drop,
+
+    /// Discard the line and mute the client for the given number of seconds
+    Mute(u64),
+
+    /// Discard the line and tell the client it is still muted for the given number of seconds. Only
+    /// happens once per connection, when a muted user reconnects
+    Muted(u64),
+
+    /// Discard the line and close the connection
+    Disconnect
+}
+
+/// A token bucket, every line costs one token and tokens come back at a steady rate up to the burst size.
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Timespec
+}
+
+impl TokenBucket {
+    fn new(burst: u32, now: Timespec) -> TokenBucket {
+        TokenBucket {
+            tokens: burst as f64,
+            last_refill: now
+        }
+    }
+
+    fn refill(&mut self, burst: u32, per_minute: u32, now: Timespec) {
+        let elapsed_ms = (now - self.last_refill).num_milliseconds();
+        if elapsed_ms > 0 {
+            self.tokens = (self.tokens + elapsed_ms as f64 * per_minute as f64 / 60000.0).min(burst as f64);
+            self.last_refill = now;
+        }
+    }
+
+    fn is_full(&self, burst: u32) -> bool {
+        self.tokens >= burst as f64
+    }
+}
+
+/// How a single connection, or the user on it, has been behaving.
+struct ConnectionFlood {
+    bucket: TokenBucket,
+
+    /// Lines over the limits since the last time the connection behaved for STRIKE_MEMORY_SECS
+    strikes: u32,
+
+    last_strike: Timespec,
+
+    /// Lines are discarded until then
+    muted_until: Option<Timespec>,
+
+    /// Has the connection been told it is muted, so it isn't told again for every line it sends
+    told_muted: bool
+}
+
+impl ConnectionFlood {
+    fn new(limits: &Limits, now: Timespec) -> ConnectionFlood {
+        ConnectionFlood {
+            bucket: TokenBucket::new(limits.message_burst, now),
+            strikes: 0,
+            last_strike: now,
+            muted_until: None,
+            told_muted: false
+        }
+    }
+
+    /// Bring the state up to date: refill the bucket, forget old strikes and end a mute that has run out.
+    fn settle(&mut self, limits: &Limits, now: Timespec) {
+        self.bucket.refill(limits.message_burst, limits.messages_per_minute, now);
+
+        if self.strikes > 0 && (now - self.last_strike).num_seconds() >= STRIKE_MEMORY_SECS {
+            self.strikes = 0;
+        }
+
+        if self.muted_until.map(|muted_until| muted_until <= now).unwrap_or(false) {
+            self.muted_until = None;
+        }
+    }
+
+    /// Would a fresh connection be treated the same
+    fn is_clean(&self, limits: &Limits) -> bool {
+        self.bucket.is_full(limits.message_burst) && self.strikes == 0 && self.muted_until.is_none()
+    }
+
+    /// Carry over how the user's earlier connection was behaving, keeping whichever is worse. Both
+    /// have to be settled first.
+    fn merge(&mut self, earlier: ConnectionFlood) {
+        self.bucket.tokens = self.bucket.tokens.min(earlier.bucket.tokens);
+
+        if earlier.strikes > self.strikes {
+            self.strikes = earlier.strikes;
+            self.last_strike = earlier.last_strike;
+        }
+
+        // The new connection hasn't been told about a mute it carried over
+        if earlier.muted_until > self.muted_until {
+            self.muted_until = earlier.muted_until;
+            self.told_muted = false;
+        }
+    }
+}
+
+/// Token bucket rate limits on the lines clients send, per user and per source IP, so a single
+/// client or a swarm of connections from one address can't flood a room.
+///
+/// Going over either limit is a strike. The first strike gets a warning, limits.flood_mute_after
+/// strikes get the connection muted for limits.flood_mute_seconds, and limits.flood_disconnect_after
+/// strikes get it disconnected.
+///
+/// Limits are kept per connection, and follow the user once it has a username. When the connection
+/// closes they are held on to under the username until they would have worn off, so reconnecting or
+/// picking the session back up with /resume doesn't start over.
+pub struct FloodControl {
+    limits: Limits,
+
+    connections: HashMap<Token, ConnectionFlood>,
+
+    /// Hashmap of connections => the lookup key of the username they last sent a line under, see `username_key`
+    user_keys: HashMap<Token, String>,
+
+    /// Hashmap of username lookup keys => how their last connection was behaving when it closed
+    departed: HashMap<String, ConnectionFlood>,
+
+    /// Shared by every connection from the same address
+    addresses: HashMap<IpAddr, TokenBucket>
+}
+
+impl FloodControl {
+    pub fn new(limits: Limits) -> FloodControl {
+        FloodControl {
+            limits: limits,
+            connections: HashMap::new(),
+            user_keys: HashMap::new(),
+            departed: HashMap::new(),
+            addresses: HashMap::new()
+        }
+    }
+
+    /// Charge a line to the connection and its address, and decide what to do with it. `user_key` is the
+    /// lookup key of the connection's username, if it has one.
+    pub fn check(&mut self, token: Token, user_key: Option<String>, address: Option<IpAddr>) -> FloodVerdict {
+        let now = time::get_time();
+        let limits = self.limits;
+
+        let conn = self.connections.entry(token).or_insert_with(|| ConnectionFlood::new(&limits, now));
+        conn.settle(&limits, now);
+
+        // The first line under a username picks up where the user's last connection left off
+        if let Some(user_key) = user_key {
+            if let Some(mut earlier) = self.departed.remove(&user_key) {
+                earlier.settle(&limits, now);
+                conn.merge(earlier);
+            }
+            self.user_keys.insert(token, user_key);
+        }
+
+        // Take a token from both buckets, whichever ones are turned on
+        let mut over_limit = false;
+        if limits.messages_per_minute > 0 {
+            if conn.bucket.tokens >= 1.0 {
+                conn.bucket.tokens -= 1.0;
+            } else {
+                over_limit = true;
+            }
+        }
+
+        if let (Some(address), true) = (address, limits.ip_messages_per_minute > 0) {
+            let bucket = self.addresses.entry(address).or_insert_with(|| TokenBucket::new(limits.ip_message_burst, now));
+            bucket.refill(limits.ip_message_burst, limits.ip_messages_per_minute, now);
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+            } else {
+                over_limit = true;
+            }
+        }
+
+        if over_limit {
+            conn.strikes += 1;
+            conn.last_strike = now;
+
+            if limits.flood_disconnect_after > 0 && conn.strikes >= limits.flood_disconnect_after {
+                return FloodVerdict::Disconnect;
+            }
+
+            // Mute again every flood_mute_after strikes, the connection only gets disconnected if it keeps going.
+            // A connection that is still muted has its mute extended without being told again
+            if limits.flood_mute_after > 0 && conn.strikes % limits.flood_mute_after == 0 {
+                let already_told = conn.muted_until.is_some() && conn.told_muted;
+                conn.muted_until = Some(now + time::Duration::seconds(limits.flood_mute_seconds as i64));
+                conn.told_muted = true;
+                if !already_told {
+                    return FloodVerdict::Mute(limits.flood_mute_seconds);
+                }
+            }
+        }
+
+        if let Some(muted_until) = conn.muted_until {
+            if conn.told_muted {
+                return FloodVerdict::Drop;
+            }
+
+            // Round up so the client is never told it is muted for 0 more seconds
+            conn.told_muted = true;
+            return FloodVerdict::Muted(((muted_until - now).num_milliseconds() as u64 + 999) / 1000);
+        }
+
+        if !over_limit {
+            FloodVerdict::Allow
+        } else if conn.strikes == 1 {
+            FloodVerdict::Warn
+        } else {
+            FloodVerdict::Drop
+        }
+    }
+
+    /// Forget a connection that was closed, holding on to its limits under its username if they haven't
+    /// worn off yet. Address buckets and held limits are dropped once they have, since a full bucket
+    /// with no strikes is the same as none at all.
+    pub fn forget(&mut self, token: Token) {
+        let now = time::get_time();
+        let limits = self.limits;
+
+        let conn = self.connections.remove(&token);
+        if let (Some(mut conn), Some(user_key)) = (conn, self.user_keys.remove(&token)) {
+            conn.settle(&limits, now);
+            self.departed.insert(user_key, conn);
+        }
+
+        let mut clean = Vec::new();
+        for (user_key, conn) in self.departed.iter_mut() {
+            conn.settle(&limits, now);
+            if conn.is_clean(&limits) {
+                clean.push(user_key.clone());
+            }
+        }
+
+        for user_key in clean {
+            self.departed.remove(&user_key);
+        }
+
+        let mut full = Vec::new();
+        for (address, bucket) in self.addresses.iter_mut() {
+            bucket.refill(limits.ip_message_burst, limits.ip_messages_per_minute, now);
+            if bucket.is_full(limits.ip_message_burst) {
+                full.push(*address);
+            }
+        }
+
+        for address in full {
+            self.addresses.remove(&address);
+        }
+    }
+}
+
+/// Failed /login attempts per source IP, so passwords can't be guessed at the speed of the rate limits.
+///
+/// After limits.max_failed_logins failures an address can't try again until limits.failed_login_lockout
+/// seconds have passed since its last failure. Successful logins don't clear the count, otherwise
+/// someone with an account of their own could log in to it between guesses.
+pub struct LoginThrottle {
+    limits: Limits,
+
+    /// Hashmap of addresses => how many logins from them have failed, and when the last one did
+    failures: HashMap<IpAddr, (u32, Timespec)>
+}
+
+impl LoginThrottle {
+    pub fn new(limits: Limits) -> LoginThrottle {
+        LoginThrottle {
+            limits: limits,
+            failures: HashMap::new()
+        }
+    }
+
+    /// Returns how many more seconds the address has to wait before it can try to log in again, or
+    /// None if it can now.
+    pub fn check(&mut self, address: Option<IpAddr>) -> Option<u64> {
+        let address = match address {
+            Some(address) if self.limits.max_failed_logins > 0 => address,
+            _ => {
+                return None;
+            }
+        };
+
+        let now = time::get_time();
+        let lockout = self.limits.failed_login_lockout as i64;
+        match self.failures.get(&address) {
+            Some(&(count, last_failure)) if (now - last_failure).num_seconds() < lockout => {
+                if count >= self.limits.max_failed_logins {
+                    return Some((lockout - (now - last_failure).num_seconds()) as u64);
+                }
+                return None;
+            },
+            Some(_) => {},
+            None => {
+                return None;
+            }
+        }
+
+        // The last failure was long enough ago to be forgotten
+        self.failures.remove(&address);
+        None
+    }
+
+    /// Count a failed login from the address. Addresses whose failures have been forgotten are dropped
+    /// at the same time, so only addresses that keep failing are held on to.
+    pub fn failed(&mut self, address: Option<IpAddr>) {
+        let address = match address {
+            Some(address) if self.limits.max_failed_logins > 0 => address,
+            _ => {
+                return;
+            }
+        };
+
+        let now = time::get_time();
+        let lockout = self.limits.failed_login_lockout as i64;
+        let forgotten: Vec<IpAddr> = self.failures.iter()
+            .filter(|&(_, &(_, last_failure))| (now - last_failure).num_seconds() >= lockout)
+            .map(|(address, _)| *address)
+            .collect();
+        for forgotten_address in forgotten {
+            self.failures.remove(&forgotten_address);
+        }
+
+        let failure = self.failures.entry(address).or_insert((0, now));
+        failure.0 += 1;
+        failure.1 = now;
+    }
+}
@@ -0,0 +1,70 @@
+/// Does the text match a shell style pattern, where `*` matches any run of characters and `?` matches
+/// any single character. Everything else has to match exactly.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+
+    // Where to go back to if the rest doesn't match: just after the last `*`, and the text position it started at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p + 1, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the last `*` swallow one more character and try again
+            p = star_p;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn literal_patterns_match_exactly() {
+        assert!(matches("ops", "ops"));
+        assert!(!matches("ops", "opsx"));
+        assert!(!matches("ops", "op"));
+        assert!(matches("", ""));
+        assert!(!matches("", "a"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(matches("r?om", "room"));
+        assert!(matches("r?om", "röom"));
+        assert!(!matches("r?om", "rom"));
+        assert!(!matches("r?om", "rooom"));
+    }
+
+    #[test]
+    fn star_matches_any_run_of_characters() {
+        assert!(matches("ops-*", "ops-"));
+        assert!(matches("ops-*", "ops-incident"));
+        assert!(matches("*", ""));
+        assert!(matches("*-*", "a-b-c"));
+        assert!(matches("a*b*c", "aXXbYYbZZc"));
+        assert!(!matches("a*b*c", "aXXbYYbZZ"));
+        assert!(!matches("ops-*", "dev-ops"));
+    }
+
+    #[test]
+    fn star_backtracks() {
+        assert!(matches("*ab", "aaab"));
+        assert!(matches("a*aab", "aaaab"));
+        assert!(!matches("*ab", "aaba"));
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::thread;
+
+use mio::{self, NotifyError, Token};
+
+use super::accounts::{check_password, hash_password};
+use super::config::LogLevel;
+use super::user::Username;
+
+/// Work for the hashing thread.
+pub enum HashJob {
+    /// Hash the password of a new account
+    Create(String),
+
+    /// Check a password against an account's hash
+    Verify(String, String)
+}
+
+/// What the hashing thread hands back to `ChatServer::notify` once a job is done.
+pub enum HashOutcome {
+    /// The hash of the new account's password, or why it couldn't be made
+    Created(Result<String, String>),
+
+    /// Whether the password matched
+    Verified(bool)
+}
+
+pub struct HashResult {
+    pub token: Token,
+
+    /// Which job this is the result of, see `PasswordHasher::finished`
+    pub id: u64,
+
+    pub outcome: HashOutcome
+}
+
+/// Password hashing is slow on purpose, so it is done on a thread of its own rather than holding up
+/// every other connection on the event loop. Results come back through the event loop's channel.
+///
+/// Each connection can have one job at a time. Slab tokens are reused, so every job gets an id and a
+/// result is only used if its connection is still waiting on that same job.
+pub struct PasswordHasher {
+    jobs: mpsc::Sender<(Token, u64, HashJob)>,
+
+    /// Hashmap of connections => the id of the job they are waiting on and the username it is for
+    pending: HashMap<Token, (u64, Username)>,
+
+    next_id: u64
+}
+
+impl PasswordHasher {
+    pub fn new(results: mio::Sender<HashResult>) -> PasswordHasher {
+        let (jobs, queue) = mpsc::channel();
+        thread::spawn(move || run_jobs(queue, results));
+
+        PasswordHasher {
+            jobs: jobs,
+            pending: HashMap::new(),
+            next_id: 0
+        }
+    }
+
+    /// Hand a job for the connection to the hashing thread, replacing any it was already waiting on.
+    pub fn submit(&mut self, token: Token, user_name: Username, job: HashJob) {
+        self.next_id += 1;
+        self.pending.insert(token, (self.next_id, user_name));
+        if self.jobs.send((token, self.next_id, job)).is_err() {
+            super::log_at(LogLevel::Error, "The password hashing thread has stopped");
+        }
+    }
+
+    /// Is the connection waiting on a job
+    pub fn is_pending(&self, token: Token) -> bool {
+        self.pending.contains_key(&token)
+    }
+
+    /// Forget the job a connection was waiting on, called when it is reset.
+    pub fn cancel(&mut self, token: Token) {
+        self.pending.remove(&token);
+    }
+
+    /// Returns the username the job was for, or None if its connection has since been reset and the
+    /// result should be ignored.
+    pub fn finished(&mut self, result: &HashResult) -> Option<Username> {
+        match self.pending.get(&result.token) {
+            Some(&(id, _)) if id == result.id => {},
+            _ => {
+                return None;
+            }
+        }
+        self.pending.remove(&result.token).map(|(_, user_name)| user_name)
+    }
+}
+
+/// The hashing thread, runs until the server drops its `PasswordHasher` or the event loop shuts down.
+fn run_jobs(queue: mpsc::Receiver<(Token, u64, HashJob)>, results: mio::Sender<HashResult>) {
+    for (token, id, job) in queue.iter() {
+        let outcome = match job {
+            HashJob::Create(password) => HashOutcome::Created(hash_password(&password)),
+            HashJob::Verify(password, hash) => HashOutcome::Verified(check_password(&password, &hash))
+        };
+
+        let mut result = HashResult { token: token, id: id, outcome: outcome };
+        loop {
+            match results.send(result) {
+                Ok(_) => {
+                    break;
+                },
+                // The event loop hasn't caught up on its channel yet
+                Err(NotifyError::Full(unsent)) => {
+                    result = unsent;
+                    thread::yield_now();
+                },
+                Err(_) => {
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::collections::vec_deque::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use time::{self, Timespec};
+
+use super::config::LogLevel;
+use super::room::Roomname;
+use super::user::Username;
+
+/// A single message broadcast to a room.
+#[derive(Clone)]
+pub struct HistoryEntry {
+    /// Position of the message in its room's history, starting at 1
+    pub sequence: u64,
+
+    pub timestamp: Timespec,
+
+    pub sender: Username,
+
+    /// The message without its trailing newline
+    pub body: String
+}
+
+/// The history of every room, persisted to disk so it survives restarts.
+///
+/// The file is a log with one message per line: the room name, sequence number, timestamp in seconds,
+/// sender and body, separated by tabs. Room names and usernames can't contain whitespace, and the body
+/// is last so it may contain tabs.
+///
+/// Only the last limits.max_history_per_room messages of each room are kept. New messages are appended
+/// to the log, and once it holds twice as many lines as are kept it is rewritten with just those.
+pub struct HistoryStore {
+    path: PathBuf,
+
+    /// The log file new messages are appended to
+    file: File,
+
+    /// Hashmap of room names => the most recent messages in that room, oldest first
+    rooms: HashMap<Roomname, VecDeque<HistoryEntry>>,
+
+    max_per_room: usize,
+
+    /// Messages kept across every room
+    kept: usize,
+
+    /// Lines in the log file, including messages that are no longer kept
+    file_lines: usize,
+
+    /// Hashmap of room names => the sequence number of the last message sent to the room
+    last_sequence: HashMap<Roomname, u64>
+}
+
+impl HistoryStore {
+    /// Load the history from the given file, creating it if it doesn't exist yet, and keep the last
+    /// `max_per_room` messages of each room.
+    pub fn load<P: AsRef<Path>>(path: P, max_per_room: usize) -> io::Result<HistoryStore> {
+        let file = try!(OpenOptions::new().read(true).append(true).create(true).open(path.as_ref()));
+        let mut store = HistoryStore {
+            path: path.as_ref().to_path_buf(),
+            file: try!(file.try_clone()),
+            rooms: HashMap::new(),
+            max_per_room: max_per_room,
+            kept: 0,
+            file_lines: 0,
+            last_sequence: HashMap::new()
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            store.file_lines += 1;
+            let fields: Vec<&str> = line.splitn(5, '\t').collect();
+            if fields.len() != 5 {
+                super::log_at(LogLevel::Warn, format!("Skipping malformed line in history: {}", line));
+                continue;
+            }
+
+            match (fields[1].parse::<u64>(), fields[2].parse::<i64>()) {
+                (Ok(sequence), Ok(seconds)) => {
+                    store.push(fields[0].to_string(), HistoryEntry {
+                        sequence: sequence,
+                        timestamp: Timespec::new(seconds, 0),
+                        sender: fields[3].to_string(),
+                        body: fields[4].to_string()
+                    });
+                },
+                _ => {
+                    super::log_at(LogLevel::Warn, format!("Skipping malformed line in history: {}", line));
+                }
+            }
+        }
+
+        // Drop what is no longer kept, along with any malformed lines
+        if store.file_lines > store.kept {
+            try!(store.compact());
+        }
+
+        Ok(store)
+    }
+
+    /// Add a message to the room's history and append it to the log file.
+    pub fn record(&mut self, room_name: &Roomname, sender: &Username, body: &str) -> HistoryEntry {
+        let entry = HistoryEntry {
+            sequence: self.last_sequence.get(room_name).cloned().unwrap_or(0) + 1,
+            timestamp: time::get_time(),
+            sender: sender.clone(),
+            // Keep each entry on a single line of the log
+            body: body.trim_right().chars().map(|c| if c == '\n' || c == '\r' { ' ' } else { c }).collect()
+        };
+
+        if let Err(e) = write_entry(&mut self.file, room_name, &entry) {
+            super::log_at(LogLevel::Error, format!("Failed to write history for room {}, {:?}", room_name, e));
+        }
+        self.file_lines += 1;
+
+        self.push(room_name.clone(), entry.clone());
+
+        if self.file_lines >= 2 * self.kept && self.file_lines > self.max_per_room {
+            if let Err(e) = self.compact() {
+                super::log_at(LogLevel::Error, format!("Failed to compact history {:?}, {:?}", self.path, e));
+            }
+        }
+        entry
+    }
+
+    /// Returns the names of every room that has history.
+    pub fn room_names(&self) -> Vec<Roomname> {
+        self.rooms.keys().cloned().collect()
+    }
+
+    /// Drop the history of rooms that were removed, in memory and on disk, so a new room that gets the
+    /// same name doesn't show anyone what was said in the old one.
+    pub fn forget(&mut self, room_names: &[Roomname]) {
+        let mut forgot = false;
+        for room_name in room_names.iter() {
+            self.last_sequence.remove(room_name);
+            if let Some(entries) = self.rooms.remove(room_name) {
+                self.kept -= entries.len();
+                forgot = true;
+            }
+        }
+
+        if forgot {
+            if let Err(e) = self.compact() {
+                super::log_at(LogLevel::Error, format!("Failed to compact history {:?}, {:?}", self.path, e));
+            }
+        }
+    }
+
+    /// Returns up to the last `count` messages sent to the room, oldest first.
+    pub fn last(&self, room_name: &Roomname, count: usize) -> Vec<HistoryEntry> {
+        match self.rooms.get(room_name) {
+            Some(entries) => {
+                let skip = entries.len().saturating_sub(count);
+                entries.iter().skip(skip).cloned().collect()
+            },
+            None => Vec::new()
+        }
+    }
+
+    fn push(&mut self, room_name: Roomname, entry: HistoryEntry) {
+        self.last_sequence.insert(room_name.clone(), entry.sequence);
+
+        let entries = self.rooms.entry(room_name).or_insert(VecDeque::new());
+        if entries.len() >= self.max_per_room {
+            entries.pop_front();
+            self.kept -= 1;
+        }
+        entries.push_back(entry);
+        self.kept += 1;
+    }
+
+    /// Rewrite the log with only the messages that are still kept. The new log is written next to the
+    /// old one and renamed over it, so nothing is lost if the server stops part way through.
+    fn compact(&mut self) -> io::Result<()> {
+        let mut new_path = self.path.clone().into_os_string();
+        new_path.push(".new");
+
+        {
+            let mut new_file = try!(File::create(&new_path));
+            for (room_name, entries) in self.rooms.iter() {
+                for entry in entries.iter() {
+                    try!(write_entry(&mut new_file, room_name, entry));
+                }
+            }
+        }
+
+        try!(fs::rename(&new_path, &self.path));
+        self.file = try!(OpenOptions::new().append(true).open(&self.path));
+        self.file_lines = self.kept;
+        Ok(())
+    }
+}
+
+fn write_entry(file: &mut File, room_name: &Roomname, entry: &HistoryEntry) -> io::Result<()> {
+    writeln!(file, "{}\t{}\t{}\t{}\t{}", room_name, entry.sequence, entry.timestamp.sec, entry.sender, entry.body)
+}
@@ -4,32 +4,122 @@ mod user;
 mod room;
 mod app;
 mod command;
+mod accounts;
+mod hasher;
+mod history;
+mod timer;
+mod flood;
+mod access;
+mod sanitize;
+mod glob;
+mod roles;
+mod bans;
+mod room_store;
+pub mod config;
 
-use std::net::SocketAddr;
-use mio::EventLoop;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::process;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use mio::{EventLoop, Token};
 use mio::tcp::TcpListener;
 use self::server::{SERVER_TOKEN, ChatServer};
+use self::accounts::AccountStore;
+use self::history::HistoryStore;
+use self::bans::BanStore;
+use self::room_store::RoomStore;
+use self::hasher::PasswordHasher;
+use self::config::{LogLevel, ServerConfig};
+
+pub use self::accounts::hash_password;
+
+/// The most verbose `LogLevel` that gets printed, stored as a usize so it can live in a static
+static LOG_LEVEL: AtomicUsize = ATOMIC_USIZE_INIT;
 
 // Easy logging for now
 pub fn log_something<T: ::std::fmt::Debug>(logged_thing: T) {
-    println!("{:?}", logged_thing)
+    log_at(LogLevel::Debug, logged_thing)
 } 
 
-pub fn run_server(address: SocketAddr) {
-	// Create a new non-blocking socket bound to the given address. All sockets
-    // created by mio are set to non-blocking mode.
-    let server = TcpListener::bind(&address).unwrap();
+/// Log something if the configured log level is at least as verbose as `level`
+pub fn log_at<T: ::std::fmt::Debug>(level: LogLevel, logged_thing: T) {
+    if level as usize <= LOG_LEVEL.load(Ordering::Relaxed) {
+        println!("{:?}", logged_thing)
+    }
+}
+
+/// Report a problem that keeps the server from running and exit, the same way invalid settings are reported.
+fn exit_with_error<T: Display>(message: T) -> ! {
+    writeln!(io::stderr(), "{}", message);
+    process::exit(1);
+}
+
+pub fn run_server(config: ServerConfig) {
+    LOG_LEVEL.store(config.log_level as usize, Ordering::Relaxed);
+
+    // Load the registered accounts so their usernames are protected from the start.
+    let mut accounts = match AccountStore::load(&config.accounts_file) {
+        Ok(accounts) => accounts,
+        Err(e) => exit_with_error(format!("Failed to load accounts from {}: {}", config.accounts_file, e))
+    };
+
+    // Operators can't /register their names, so their accounts are created from the config.
+    for &(ref name, ref hash) in config.operator_passwords.iter() {
+        if !accounts.contains(name) {
+            if let Err(e) = accounts.create(name, hash.clone()) {
+                exit_with_error(format!("Failed to create the account of operator {}: {}", name, e));
+            }
+        }
+    }
+
+    // Load the history so rooms pick up where they left off before the last restart.
+    let history = match HistoryStore::load(&config.history_file, config.limits.max_history_per_room) {
+        Ok(history) => history,
+        Err(e) => exit_with_error(format!("Failed to load history from {}: {}", config.history_file, e))
+    };
+
+    // Load the bans so they stay in place across restarts.
+    let bans = match BanStore::load(&config.bans_file) {
+        Ok(bans) => bans,
+        Err(e) => exit_with_error(format!("Failed to load bans from {}: {}", config.bans_file, e))
+    };
+
+    // Load the rooms that were made persistent, and their topics.
+    let rooms = match RoomStore::load(&config.rooms_file) {
+        Ok(rooms) => rooms,
+        Err(e) => exit_with_error(format!("Failed to load rooms from {}: {}", config.rooms_file, e))
+    };
 
     // Create a new `EventLoop`. 
-    let mut event_loop = EventLoop::new().unwrap();
+    let mut event_loop = match EventLoop::new() {
+        Ok(event_loop) => event_loop,
+        Err(e) => exit_with_error(format!("Failed to create the event loop: {}", e))
+    };
+
+    // Create a new non-blocking socket bound to each of the given addresses, and register them
+    // with the event loop. All sockets created by mio are set to non-blocking mode.
+    let mut listeners = Vec::new();
+    for (i, address) in config.listen_addresses.iter().enumerate() {
+        let listener = match TcpListener::bind(address) {
+            Ok(listener) => listener,
+            Err(e) => exit_with_error(format!("Failed to listen on {}: {}", address, e))
+        };
+        if let Err(e) = event_loop.register(&listener, Token(SERVER_TOKEN.0 + i)) {
+            exit_with_error(format!("Failed to listen on {}: {}", address, e));
+        }
+        listeners.push(listener);
+
+        println!("running chat server; ip={} port={}", address.ip(), address.port());
+    }
 
-    // Register the server socket with the event loop.
-    event_loop.register(&server, server::SERVER_TOKEN).unwrap();
+    // Passwords are hashed on a thread of their own, which hands results back through the event loop.
+    let hasher = PasswordHasher::new(event_loop.channel());
 
     // Create a new `ChatServer` instance that will track the state of the server.
-    let mut pong = ChatServer::new(server);
+    let mut pong = ChatServer::new(listeners, config, accounts, history, bans, rooms, hasher);
 
     // Run the `ChatServer` server
-    println!("running chat server; ip={} port={}", address.ip(), address.port());
-    event_loop.run(&mut pong).unwrap();
+    if let Err(e) = event_loop.run(&mut pong) {
+        exit_with_error(format!("The event loop stopped: {}", e));
+    }
 }
@@ -0,0 +1,62 @@
+/// What a user can do across the whole server.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ServerRole {
+    User,
+
+    /// Has every permission in every room. Only given to the registered accounts listed in the
+    /// `operators` setting, once they have logged in.
+    Operator
+}
+
+/// What a user can do in a single room, each role can do everything the ones before it can.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum RoomRole {
+    /// Everyone in the room who hasn't been given another role
+    Member,
+
+    /// Can change the topic
+    Voiced,
+
+    /// Can give and take voice
+    Moderator,
+
+    /// Can give and take every role below owner, whoever created the room starts out as its owner
+    Owner
+}
+
+impl RoomRole {
+    pub fn parse(name: &str) -> Option<RoomRole> {
+        match name {
+            "member" => Some(RoomRole::Member),
+            "voiced" => Some(RoomRole::Voiced),
+            "moderator" => Some(RoomRole::Moderator),
+            "owner" => Some(RoomRole::Owner),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RoomRole::Member => "member",
+            RoomRole::Voiced => "voiced",
+            RoomRole::Moderator => "moderator",
+            RoomRole::Owner => "owner"
+        }
+    }
+}
+
+/// What a user needs before a command is run, see `ChatCommand::permission`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Permission {
+    /// Anyone, even before picking a username
+    Anyone,
+
+    /// Anyone with a username
+    User,
+
+    /// At least this role in the user's current room
+    Room(RoomRole),
+
+    /// Server operators only
+    Operator
+}
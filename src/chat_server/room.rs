@@ -1,18 +1,138 @@
 use mio::Token;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use time::{self, Timespec};
+
+use super::roles::RoomRole;
+use super::user::Username;
 
 pub type Roomname = String;
 
+/// What the room is currently about, set with /topic.
+#[derive(Clone)]
+pub struct Topic {
+	pub text: String,
+	pub set_by: Username,
+	pub set_at: Timespec
+}
+
 pub struct ChatRoom {
 	pub name: Roomname,
-	pub members: HashSet<Token>
+	pub members: HashSet<Token>,
+	pub topic: Option<Topic>,
+
+	/// Persistent rooms stay when everyone leaves, the others are removed once they have been empty
+	/// for limits.empty_room_grace_period
+	pub persistent: bool,
+
+	/// When the last member left, None while there are members
+	pub empty_since: Option<Timespec>,
+
+	/// Hashmap of username lookup keys => roles other than member, see `username_key`. Kept by name so
+	/// registered users keep their roles when they reconnect
+	pub roles: HashMap<String, RoomRole>,
+
+	/// Hashmap of username lookup keys => when their mute runs out, None if it lasts until /unmute.
+	/// Muted users can stay in the room but nothing they say is broadcast
+	pub mutes: HashMap<String, Option<Timespec>>,
+
+	/// Only users who have been invited can join
+	pub invite_only: bool,
+
+	/// Users who haven't been invited have to give this with /join
+	pub key: Option<String>,
+
+	/// Left out of /rooms and /who for anyone who isn't in the room
+	pub hidden: bool,
+
+	/// Only voiced users and above can send messages to the room
+	pub read_only: bool,
+
+	/// Username lookup keys of users invited with /invite, each invite is used up when they join
+	pub invites: HashSet<String>,
+
+	/// When a message was last sent to the room, or when it was created if there haven't been any
+	pub last_active: Timespec
 }
 
 impl ChatRoom {
-	pub fn new(name: Roomname) -> ChatRoom {
+	pub fn new(name: Roomname, persistent: bool) -> ChatRoom {
 		ChatRoom {
 			name: name,
-			members: HashSet::new()
+			members: HashSet::new(),
+			topic: None,
+			roles: HashMap::new(),
+			mutes: HashMap::new(),
+			invite_only: false,
+			key: None,
+			hidden: false,
+			read_only: false,
+			invites: HashSet::new(),
+			persistent: persistent,
+			empty_since: Some(time::get_time()),
+			last_active: time::get_time()
 		}
 	}
-}
\ No newline at end of file
+
+	pub fn join(&mut self, token: Token) {
+		self.members.insert(token);
+		self.empty_since = None;
+	}
+
+	pub fn leave(&mut self, token: Token) {
+		self.members.remove(&token);
+		if self.members.is_empty() {
+			self.empty_since = Some(time::get_time());
+		}
+	}
+
+	pub fn role_of(&self, user_key: &str) -> RoomRole {
+		self.roles.get(user_key).cloned().unwrap_or(RoomRole::Member)
+	}
+
+	pub fn set_role(&mut self, user_key: String, role: RoomRole) {
+		if role == RoomRole::Member {
+			self.roles.remove(&user_key);
+		} else {
+			self.roles.insert(user_key, role);
+		}
+	}
+
+	pub fn is_muted(&self, user_key: &str) -> bool {
+		match self.mutes.get(user_key) {
+			Some(&Some(until)) => until > time::get_time(),
+			Some(&None) => true,
+			None => false
+		}
+	}
+
+	/// The room's modes as shown in /rooms and /mode, e.g. "+ik", or an empty string if it has none.
+	pub fn modes(&self) -> String {
+		let mut modes = String::new();
+		if self.invite_only {
+			modes.push('i');
+		}
+		if self.key.is_some() {
+			modes.push('k');
+		}
+		if self.hidden {
+			modes.push('h');
+		}
+		if self.read_only {
+			modes.push('r');
+		}
+
+		if modes.is_empty() {
+			modes
+		} else {
+			format!("+{}", modes)
+		}
+	}
+
+	pub fn set_topic(&mut self, text: String, set_by: Username) {
+		self.topic = Some(Topic {
+			text: text,
+			set_by: set_by,
+			set_at: time::get_time()
+		});
+	}
+}
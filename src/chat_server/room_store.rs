@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use time::Timespec;
+
+use super::config::LogLevel;
+use super::roles::RoomRole;
+use super::room::{ChatRoom, Roomname, Topic};
+
+/// Persistent rooms along with their topics, modes and roles, saved to disk so they survive restarts.
+/// Ephemeral rooms are removed once they are empty, so there is nothing to keep for them.
+///
+/// The file holds one room per line, with these fields separated by spaces:
+///
+/// - the room's name
+/// - its modes as shown by /mode, or `-` if it has none
+/// - its key, or `-` if it has none. Only read when the modes include `k`, so `-` can be a key too
+/// - its roles as comma separated `name:role` pairs, or `-` if no one has one
+/// - if it has a topic, when the topic was set in seconds since the epoch, who set it and the topic
+///   itself
+///
+/// Only the topic can contain whitespace. The whole file is rewritten whenever a room is made
+/// persistent or not, or a persistent room's topic, modes or roles change.
+pub struct RoomStore {
+    path: PathBuf,
+
+    /// The rooms that were in the file when it was loaded
+    loaded: Vec<SavedRoom>
+}
+
+/// A room as it was saved before the last restart.
+#[derive(Clone)]
+pub struct SavedRoom {
+    pub name: Roomname,
+    pub topic: Option<Topic>,
+    pub invite_only: bool,
+    pub key: Option<String>,
+    pub hidden: bool,
+    pub read_only: bool,
+
+    /// Hashmap of username lookup keys => roles other than member
+    pub roles: HashMap<String, RoomRole>
+}
+
+impl RoomStore {
+    /// Load the rooms from the given file. A missing file is treated as no rooms and will be created
+    /// when the first one is saved.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<RoomStore> {
+        let mut store = RoomStore {
+            path: path.as_ref().to_path_buf(),
+            loaded: Vec::new()
+        };
+
+        let file = match File::open(&store.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(store);
+            },
+            Err(e) => {
+                return Err(e);
+            }
+        };
+
+        for line in BufReader::new(file).lines() {
+            let line = try!(line);
+            match parse_room(&line) {
+                Some(room) => store.loaded.push(room),
+                None => {
+                    super::log_at(LogLevel::Warn, format!("Skipping malformed line in rooms: {}", line));
+                }
+            }
+        }
+
+        Ok(store)
+    }
+
+    /// The rooms that were saved before the last restart.
+    pub fn loaded(&self) -> &[SavedRoom] {
+        &self.loaded
+    }
+
+    /// Rewrite the file with every persistent room among `rooms`.
+    pub fn save<'a, I: Iterator<Item = &'a ChatRoom>>(&self, rooms: I) -> Result<(), String> {
+        let saved = File::create(&self.path).and_then(|mut file| {
+            for room in rooms.filter(|room| room.persistent) {
+                let modes = room.modes();
+                let mut roles: Vec<String> = room.roles.iter()
+                    .map(|(name, role)| format!("{}:{}", name, role.name()))
+                    .collect();
+                roles.sort();
+
+                try!(write!(file, "{} {} {} {}",
+                    room.name,
+                    if modes.is_empty() { "-" } else { &modes },
+                    room.key.as_ref().map(|key| &key[..]).unwrap_or("-"),
+                    if roles.is_empty() { "-".to_string() } else { roles.join(",") }));
+                match room.topic {
+                    Some(ref topic) => try!(writeln!(file, " {} {} {}", topic.set_at.sec, topic.set_by, topic.text)),
+                    None => try!(writeln!(file, ""))
+                }
+            }
+            Ok(())
+        });
+
+        if let Err(e) = saved {
+            super::log_at(LogLevel::Error, format!("Failed to save rooms {:?}, {:?}", self.path, e));
+            return Err("Failed to save the room, please try again later".into());
+        }
+        Ok(())
+    }
+}
+
+/// Parse a line of the rooms file, see `RoomStore`.
+fn parse_room(line: &str) -> Option<SavedRoom> {
+    let fields: Vec<&str> = line.splitn(7, ' ').collect();
+    if fields.len() != 4 && fields.len() != 7 {
+        return None;
+    }
+
+    let modes = fields[1];
+    if modes != "-" && !modes.starts_with('+') {
+        return None;
+    }
+
+    let mut roles = HashMap::new();
+    if fields[3] != "-" {
+        for pair in fields[3].split(',') {
+            let mut split = pair.splitn(2, ':');
+            match (split.next(), split.next().and_then(RoomRole::parse)) {
+                (Some(name), Some(role)) if !name.is_empty() => {
+                    roles.insert(name.to_string(), role);
+                },
+                _ => {
+                    return None;
+                }
+            }
+        }
+    }
+
+    let topic = if fields.len() == 7 {
+        let seconds = match fields[4].parse::<i64>() {
+            Ok(seconds) => seconds,
+            Err(_) => {
+                return None;
+            }
+        };
+        Some(Topic {
+            text: fields[6].to_string(),
+            set_by: fields[5].to_string(),
+            set_at: Timespec::new(seconds, 0)
+        })
+    } else {
+        None
+    };
+
+    Some(SavedRoom {
+        name: fields[0].to_string(),
+        topic: topic,
+        invite_only: modes.contains('i'),
+        key: if modes.contains('k') { Some(fields[2].to_string()) } else { None },
+        hidden: modes.contains('h'),
+        read_only: modes.contains('r'),
+        roles: roles
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use rand::{thread_rng, Rng};
+    use time::Timespec;
+    use super::RoomStore;
+    use super::super::roles::RoomRole;
+    use super::super::room::{ChatRoom, Topic};
+
+    #[test]
+    fn save_and_load() {
+        let name: String = thread_rng().gen_ascii_chars().take(12).collect();
+        let path = env::temp_dir().join(format!("simple_chat_rooms_{}.txt", name));
+
+        let plain = ChatRoom::new("plain".to_string(), true);
+
+        let mut private = ChatRoom::new("private".to_string(), true);
+        private.topic = Some(Topic {
+            text: "what  we talk about".to_string(),
+            set_by: "alice".to_string(),
+            set_at: Timespec::new(1500000000, 0)
+        });
+        private.invite_only = true;
+        private.key = Some("-".to_string());
+        private.hidden = true;
+        private.read_only = true;
+        private.set_role("alice".to_string(), RoomRole::Owner);
+        private.set_role("bob".to_string(), RoomRole::Voiced);
+
+        let ephemeral = ChatRoom::new("ephemeral".to_string(), false);
+
+        let store = RoomStore::load(&path).unwrap();
+        assert!(store.loaded().is_empty());
+        store.save(vec![&plain, &private, &ephemeral].into_iter()).unwrap();
+
+        let loaded = RoomStore::load(&path).unwrap().loaded().to_vec();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+
+        assert_eq!(loaded[0].name, "plain");
+        assert!(loaded[0].topic.is_none());
+        assert!(!loaded[0].invite_only && !loaded[0].hidden && !loaded[0].read_only);
+        assert_eq!(loaded[0].key, None);
+        assert!(loaded[0].roles.is_empty());
+
+        assert_eq!(loaded[1].name, "private");
+        let topic = loaded[1].topic.clone().unwrap();
+        assert_eq!((&topic.text[..], &topic.set_by[..], topic.set_at.sec), ("what  we talk about", "alice", 1500000000));
+        assert!(loaded[1].invite_only && loaded[1].hidden && loaded[1].read_only);
+        assert_eq!(loaded[1].key, Some("-".to_string()));
+        assert_eq!(loaded[1].roles, private.roles);
+    }
+}
@@ -0,0 +1,220 @@
+use super::config::{ControlCharPolicy, ServerConfig};
+
+const ESC: char = '\u{1b}';
+
+/// Resets colors and styles, sent after a message that set any so they don't bleed into the next line
+const SGR_RESET: &'static str = "\u{1b}[0m";
+
+/// Cleans up text written by users before it is relayed to anyone else's terminal.
+///
+/// Control characters, escape sequences and invisible Unicode that reorders or hides text are either
+/// stripped or escaped into something visible like `\x1b`, depending on the `control_characters`
+/// setting. With `allow_colors` turned on, escape sequences that only set colors and basic styles are
+/// passed through.
+pub struct Sanitizer {
+    policy: ControlCharPolicy,
+    allow_colors: bool
+}
+
+impl Sanitizer {
+    pub fn new(config: &ServerConfig) -> Sanitizer {
+        Sanitizer {
+            policy: config.control_characters,
+            allow_colors: config.allow_colors
+        }
+    }
+
+    pub fn clean(&self, text: &str) -> String {
+        let mut clean = String::with_capacity(text.len());
+        let mut set_colors = false;
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c == ESC {
+                let mut sequence = String::new();
+                sequence.push(c);
+                read_escape_sequence(&mut chars, &mut sequence);
+
+                if self.allow_colors && is_safe_sgr(&sequence) {
+                    clean.push_str(&sequence);
+                    set_colors = true;
+                } else if self.policy == ControlCharPolicy::Escape {
+                    // ESC and any other control characters in the sequence are escaped, the printable rest is kept
+                    for c in sequence.chars() {
+                        push_escaped(&mut clean, c);
+                    }
+                }
+            } else if is_unsafe(c) {
+                if self.policy == ControlCharPolicy::Escape {
+                    push_escaped(&mut clean, c);
+                }
+            } else {
+                clean.push(c);
+            }
+        }
+
+        if set_colors {
+            clean.push_str(SGR_RESET);
+        }
+        clean
+    }
+}
+
+/// Write out a character that isn't safe to relay as something visible, like `\x1b` or `\u{202e}`.
+fn push_escaped(clean: &mut String, c: char) {
+    if !is_unsafe(c) {
+        clean.push(c);
+    } else if (c as u32) < 0x100 {
+        clean.push_str(&format!("\\x{:02x}", c as u32));
+    } else {
+        clean.push_str(&format!("\\u{{{:x}}}", c as u32));
+    }
+}
+
+/// Characters that aren't safe to show as they are: control characters other than tab (Unicode
+/// category Cc), format characters (Cf) like bidi overrides, zero width spaces, soft hyphens and tag
+/// characters, the line and paragraph separators (Zl and Zp), and the Hangul fillers, which are
+/// letters that draw as blank space.
+pub fn is_unsafe(c: char) -> bool {
+    match c {
+        '\t' => false,
+
+        // Cc
+        '\u{0}'...'\u{1f}' | '\u{7f}'...'\u{9f}' => true,
+
+        // Cf
+        '\u{ad}' | '\u{600}'...'\u{605}' | '\u{61c}' | '\u{6dd}' | '\u{70f}' | '\u{8e2}' | '\u{180e}' => true,
+        '\u{200b}'...'\u{200f}' | '\u{202a}'...'\u{202e}' | '\u{2060}'...'\u{206f}' | '\u{feff}' => true,
+        '\u{fff9}'...'\u{fffb}' | '\u{110bd}' | '\u{110cd}' | '\u{13430}'...'\u{1343f}' => true,
+        '\u{1bca0}'...'\u{1bca3}' | '\u{1d173}'...'\u{1d17a}' | '\u{e0000}'...'\u{e007f}' => true,
+
+        // Zl and Zp
+        '\u{2028}' | '\u{2029}' => true,
+
+        // Hangul fillers
+        '\u{115f}' | '\u{1160}' | '\u{3164}' | '\u{ffa0}' => true,
+
+        _ => false
+    }
+}
+
+/// Read the rest of an escape sequence that started with ESC into `sequence`. CSI sequences run up to
+/// their final byte, OSC sequences up to BEL or ESC \, and anything else is a single character.
+fn read_escape_sequence<I: Iterator<Item = char>>(chars: &mut ::std::iter::Peekable<I>, sequence: &mut String) {
+    match chars.next() {
+        Some('[') => {
+            sequence.push('[');
+            while let Some(c) = chars.next() {
+                sequence.push(c);
+                if c >= '\u{40}' && c <= '\u{7e}' {
+                    break;
+                }
+            }
+        },
+        Some(']') => {
+            sequence.push(']');
+            while let Some(c) = chars.next() {
+                sequence.push(c);
+                if c == '\u{7}' {
+                    break;
+                }
+                if c == ESC && chars.peek() == Some(&'\\') {
+                    sequence.push(chars.next().unwrap());
+                    break;
+                }
+            }
+        },
+        Some(c) => {
+            sequence.push(c);
+        },
+        None => {}
+    }
+}
+
+/// Is the escape sequence a Select Graphic Rendition that only resets, sets bold, underline or
+/// reverse video, or picks one of the 16 basic foreground or background colors.
+fn is_safe_sgr(sequence: &str) -> bool {
+    if !sequence.starts_with("\u{1b}[") || !sequence.ends_with('m') {
+        return false;
+    }
+
+    let params = &sequence[2..sequence.len() - 1];
+    params.split(';').all(|param| {
+        match param.parse::<u32>() {
+            Ok(n) => match n {
+                0 | 1 | 4 | 7 | 22 | 24 | 27 | 30...37 | 39 | 40...47 | 49 | 90...97 | 100...107 => true,
+                _ => false
+            },
+            // An empty parameter means 0
+            Err(_) => param.is_empty()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::{ControlCharPolicy, ServerConfig};
+    use super::Sanitizer;
+
+    fn sanitizer(policy: ControlCharPolicy, allow_colors: bool) -> Sanitizer {
+        let mut config = ServerConfig::default();
+        config.control_characters = policy;
+        config.allow_colors = allow_colors;
+        Sanitizer::new(&config)
+    }
+
+    #[test]
+    fn plain_text_is_left_alone() {
+        let strip = sanitizer(ControlCharPolicy::Strip, false);
+        assert_eq!(strip.clean("hello\tworld, zoë 👋"), "hello\tworld, zoë 👋");
+    }
+
+    #[test]
+    fn control_characters_are_stripped() {
+        let strip = sanitizer(ControlCharPolicy::Strip, false);
+        assert_eq!(strip.clean("a\u{7}b\u{8}c\rd\u{9b}e"), "abcde");
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        let escape = sanitizer(ControlCharPolicy::Escape, false);
+        assert_eq!(escape.clean("a\u{7}b\u{202e}c"), "a\\x07b\\u{202e}c");
+    }
+
+    #[test]
+    fn invisible_characters_are_stripped() {
+        let strip = sanitizer(ControlCharPolicy::Strip, false);
+        let invisible = "\u{ad}\u{61c}\u{180e}\u{200b}\u{200d}\u{202a}\u{2028}\u{2029}\u{2066}\u{feff}\u{fff9}\u{115f}\u{1160}\u{3164}\u{ffa0}\u{e0001}\u{e0041}";
+        assert_eq!(strip.clean(&format!("a{}b", invisible)), "ab");
+    }
+
+    #[test]
+    fn escape_sequences_are_removed_whole() {
+        let strip = sanitizer(ControlCharPolicy::Strip, false);
+        assert_eq!(strip.clean("a\u{1b}[2Jb"), "ab");
+        assert_eq!(strip.clean("a\u{1b}]0;title\u{7}b"), "ab");
+        assert_eq!(strip.clean("a\u{1b}]0;title\u{1b}\\b"), "ab");
+        assert_eq!(strip.clean("a\u{1b}cb"), "ab");
+        assert_eq!(strip.clean("a\u{1b}"), "a");
+    }
+
+    #[test]
+    fn escaped_sequences_keep_their_printable_part() {
+        let escape = sanitizer(ControlCharPolicy::Escape, false);
+        assert_eq!(escape.clean("\u{1b}[2J"), "\\x1b[2J");
+    }
+
+    #[test]
+    fn colors_pass_through_only_when_allowed() {
+        let colors = sanitizer(ControlCharPolicy::Strip, true);
+        assert_eq!(colors.clean("\u{1b}[1;31mred"), "\u{1b}[1;31mred\u{1b}[0m");
+        assert_eq!(colors.clean("\u{1b}[mplain"), "\u{1b}[mplain\u{1b}[0m");
+
+        // 256 colors and cursor movement aren't basic colors
+        assert_eq!(colors.clean("\u{1b}[38;5;196mred"), "red");
+        assert_eq!(colors.clean("\u{1b}[2Aup"), "up");
+
+        let no_colors = sanitizer(ControlCharPolicy::Strip, false);
+        assert_eq!(no_colors.clean("\u{1b}[31mred"), "red");
+    }
+}
@@ -1,73 +1,230 @@
 use mio;
-use mio::{Token, EventLoop, EventSet, PollOpt};
+use mio::{Token, EventLoop, EventSet, PollOpt, TryWrite};
 use mio::tcp::*;
 use mio::util::Slab;
 use time;
+use time::Timespec;
 
+use std::cmp::Ordering;
 use std::io::ErrorKind;
+use std::net::IpAddr;
 use std::rc::Rc;
 
+use super::accounts::AccountStore;
 use super::app::ChatApp;
-use super::connection::ChatConnection;
-use super::command::{is_command, ChatCommand};
+use super::bans::BanStore;
+use super::config::{LogLevel, ServerConfig};
+use super::history::{HistoryEntry, HistoryStore};
+use super::glob;
+use super::roles::{RoomRole, ServerRole};
+use super::room::{ChatRoom, Roomname, Topic};
+use super::room_store::RoomStore;
+use super::connection::{ChatConnection, OverflowCounts};
+use super::command::{is_command, ChatCommand, RoomListQuery, RoomSort};
+use super::timer::{TimerEvent, TimerKind, Timers};
+use super::flood::{FloodControl, FloodVerdict, LoginThrottle};
+use super::hasher::{HashJob, HashOutcome, HashResult, PasswordHasher};
+use super::sanitize::Sanitizer;
+use super::user::{username_key, Presence, Username};
 
-/// The token for the tcp listener socket.
+/// The token for the first tcp listener socket, any others follow it.
 /// kqueue has some wierd behaviors when the server is Token(0) so we'll use token 1.
 pub const SERVER_TOKEN: Token = Token(1);
 
 /// Represents the server's connection for the chat app
 pub struct ChatServer {
-    /// The tcp connection the server listens on
-    server: TcpListener,
+    /// The tcp connections the server listens on, the listener at index i is registered with token SERVER_TOKEN + i
+    listeners: Vec<TcpListener>,
 
     /// All the connections to the chat server, indexed by their token.
     connections: Slab<ChatConnection>,
 
+    config: ServerConfig,
+
+    /// How often the send queue limits kicked in for connections that have since been closed
+    overflow_totals: OverflowCounts,
+
+    /// Delayed work scheduled on the event loop, delivered back to `timeout`
+    timers: Timers,
+
+    /// Rate limits on the lines clients send
+    flood: FloodControl,
+
+    /// Failed /login attempts per address
+    login_throttle: LoginThrottle,
+
+    /// Hashes and checks passwords for /register and /login off the event loop
+    hasher: PasswordHasher,
+
+    /// Cleans up what users write before it is relayed
+    sanitizer: Sanitizer,
+
     app: ChatApp
 }
 
 impl ChatServer {
-    // Initialize a new `ChatServer` server from the given TCP listener socket
-    pub fn new(server: TcpListener) -> ChatServer {
+    // Initialize a new `ChatServer` server from the given TCP listener sockets
+    pub fn new(listeners: Vec<TcpListener>, config: ServerConfig, accounts: AccountStore, history: HistoryStore, bans: BanStore, rooms: RoomStore, hasher: PasswordHasher) -> ChatServer {
 
         ChatServer {
-            server: server,
-            connections: Slab::new_starting_at(Token(SERVER_TOKEN.0 + 1), 1024),
-            app: ChatApp::new()
+            connections: Slab::new_starting_at(Token(SERVER_TOKEN.0 + listeners.len()), config.limits.max_connections),
+            listeners: listeners,
+            app: ChatApp::new(&config, accounts, history, bans, rooms),
+            flood: FloodControl::new(config.limits),
+            login_throttle: LoginThrottle::new(config.limits),
+            hasher: hasher,
+            sanitizer: Sanitizer::new(&config),
+            config: config,
+            overflow_totals: OverflowCounts::default(),
+            timers: Timers::new()
         }
     }
 
+    /// Is this the token of one of the listener sockets rather than a client connection
+    fn is_listener(&self, token: Token) -> bool {
+        token.0 >= SERVER_TOKEN.0 && token.0 < SERVER_TOKEN.0 + self.listeners.len()
+    }
+
     /// Function that is called when the chat server recieves a call to ready and the event set contains readable
     /// Handles all logic related to reading from any connection besides the server connection
     fn read(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token) {
 
-        // If we get Some back, then the message has been fully recieved and we can handle it accordingly
-        match self.connections[token].read()
-        {
-            Ok(Some(message)) => {
-                self.handle_message_read_from_client(event_loop, token, message);
-            },
-            Ok(None) => {
-                // Nothing was read from the client, or a newline has not be encountered yet
-                // Either way, just keep listening.
+        if let Err(e) = self.connections[token].read() {
+            self.handle_error_when_reading_from_client(token, e);
+        }
+
+        self.handle_lines(event_loop, token);
+    }
+
+    /// Handle every complete line that has arrived. A partial line stays buffered in the connection
+    /// until the rest of it is read.
+    fn handle_lines(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token) {
+        let mut finished_a_line = false;
+        loop {
+            // Handling a message may have reset this connection while notifying others
+            if !self.connections.contains(token) {
+                return;
             }
-            Err(e) => {
-                self.handle_error_when_reading_from_client(token, e);
+
+            // Anything sent after a /quit, or once the connection failed, is thrown away with it
+            if self.connections[token].is_closed() {
+                break;
+            }
+
+            // Lines sent after a /register or /login wait until its password has been checked, they
+            // may depend on it. See `notify`
+            if self.hasher.is_pending(token) {
+                break;
+            }
+
+            match self.connections[token].next_line() {
+                Some(Ok(message)) => {
+                    finished_a_line = true;
+                    self.handle_message_read_from_client(event_loop, token, message);
+                },
+                Some(Err(e)) => {
+                    finished_a_line = true;
+                    self.handle_error_when_reading_from_client(token, e);
+                },
+                None => {
+                    break;
+                }
             }
         }
 
         if self.connections[token].is_closed() {
             self.reset_connection(event_loop, token);
         } else {
+            self.update_partial_line_timeout(event_loop, token, finished_a_line);
             self.reregister(event_loop, token);
         }
+
+        // Any of the lines may have left a room empty
+        self.schedule_room_expiry(event_loop);
+    }
+
+    /// Start the partial line timer if the connection is left with part of a line, and stop it once
+    /// there isn't one. The timer restarts for every line, so it covers how long a single line takes.
+    fn update_partial_line_timeout(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, finished_a_line: bool) {
+        let timeout_secs = self.config.limits.partial_line_timeout;
+        let has_partial_line = self.connections[token].has_partial_line();
+
+        if finished_a_line || !has_partial_line {
+            self.timers.cancel(event_loop, token, TimerKind::PartialLine);
+        }
+
+        // If the timer is still running the connection is waiting on the same line as before
+        if timeout_secs > 0 && has_partial_line && !self.timers.is_scheduled(token, TimerKind::PartialLine) {
+            self.timers.schedule(event_loop, token, TimerKind::PartialLine, timeout_secs);
+        }
+    }
+
+    /// Make sure the next empty room is removed once its grace period is up, so rooms nobody is in don't
+    /// linger until someone happens to look. A timer that is already running fires no later than it
+    /// should, every room that empties after it was scheduled is due after it.
+    fn schedule_room_expiry(&mut self, event_loop: &mut EventLoop<ChatServer>) {
+        if self.timers.is_scheduled(SERVER_TOKEN, TimerKind::EmptyRooms) {
+            return;
+        }
+
+        // Round up a second so the grace period is over when it fires
+        if let Some(delay_secs) = self.app.next_room_expiry() {
+            self.timers.schedule(event_loop, SERVER_TOKEN, TimerKind::EmptyRooms, delay_secs + 1);
+        }
+    }
+
+    /// Queue a last message for a connection, try to get it out straight away and then close the connection.
+    fn close_with_notice(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, notice: &str) {
+        {
+            let conn = self.get_connection(token);
+            conn.send_message(Rc::new(format!("Server: {}\n", notice).into_bytes()));
+            conn.write();
+        }
+        self.reset_connection(event_loop, token);
+    }
+
+    /// Disconnect the connection if it hasn't sent anything for limits.idle_timeout, otherwise check again when it could have.
+    fn check_idle(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token) {
+        let idle_timeout = self.config.limits.idle_timeout;
+        let idle_secs = self.connections[token].idle_time().num_seconds() as u64;
+
+        if idle_secs >= idle_timeout {
+            super::log_at(LogLevel::Info, format!("Closing {:?}, it has been idle for {} seconds", token, idle_secs));
+            self.close_with_notice(event_loop, token, "Disconnected for being idle too long");
+        } else {
+            self.timers.schedule(event_loop, token, TimerKind::Idle, idle_timeout - idle_secs);
+        }
+    }
+
+    /// PING the connection if it hasn't sent anything for limits.keepalive_interval. Writing to a dead
+    /// connection is how it gets noticed, and a reply counts as the client sending something.
+    fn send_keepalive(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token) {
+        let interval = self.config.limits.keepalive_interval;
+        let idle_secs = self.connections[token].idle_time().num_seconds() as u64;
+
+        if idle_secs < interval {
+            self.timers.schedule(event_loop, token, TimerKind::Keepalive, interval - idle_secs);
+            return;
+        }
+
+        let failed = {
+            let conn = self.get_connection(token);
+            conn.send_message(Rc::new("Server: PING\n".to_string().into_bytes()));
+            conn.is_closed() || conn.reregister(event_loop).is_err()
+        };
+
+        if failed {
+            self.reset_connection(event_loop, token);
+        } else {
+            self.timers.schedule(event_loop, token, TimerKind::Keepalive, interval);
+        }
     }
 
     /// Function that is called when the chat server recieves a call to ready and the event set contains writable
     /// Handles all logic related to writing to any client connections
     fn write(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token) {
         super::log_something(format!("Write event for {:?}", token));
-        assert!(SERVER_TOKEN != token, "Received writable event for Server");
+        assert!(!self.is_listener(token), "Received writable event for Server");
 
         self.get_connection(token).write();
 
@@ -81,9 +238,10 @@ impl ChatServer {
     fn handle_error_when_reading_from_client(&mut self, token: Token, error: ::std::io::Error) {
         // TODO, maybe need different behavior for different variants?
         match error.kind() {
+            // Invalid utf8 or a line that was too long
             ErrorKind::InvalidInput => {
-                super::log_something("Data read from connection was not valid utf8");
-                self.connections[token].send_message(Rc::new("Server: Invalid utf8, message was discarded.\n".to_string().into_bytes()));
+                super::log_something(format!("Discarded a line from {:?}, {}", token, error));
+                self.connections[token].send_message(Rc::new(format!("Server: {}, message was discarded.\n", error).into_bytes()));
             },
             _ => {
             }
@@ -91,32 +249,74 @@ impl ChatServer {
     }
 
     fn handle_message_read_from_client(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, message: String) {
+        // A client that is being held back can still answer a PING or leave
+        let command = ChatCommand::new(&message);
+        let exempt = match command {
+            Some(ChatCommand::Pong) | Some(ChatCommand::Quit) => true,
+            _ => false
+        };
+
+        // Lines over the rate limits are discarded before they can reach anyone else's send queue
+        let peer_ip = self.connections[token].peer_ip();
+        let user_key = self.app.get_username(token).map(|user_name| username_key(&user_name));
+        let verdict = if exempt { FloodVerdict::Allow } else { self.flood.check(token, user_key, peer_ip) };
+        let reply = match verdict {
+            FloodVerdict::Allow => None,
+            FloodVerdict::Warn => Some("Server: You are sending messages too fast, slow down or they will be discarded\n".to_string()),
+            FloodVerdict::Drop => {
+                return;
+            },
+            FloodVerdict::Mute(secs) => {
+                super::log_at(LogLevel::Info, format!("Muting {:?} from {:?} for {} seconds for flooding", token, peer_ip, secs));
+                Some(format!("Server: You have been muted for {} seconds for flooding\n", secs))
+            },
+            FloodVerdict::Muted(secs) => Some(format!("Server: You are muted for {} more seconds\n", secs)),
+            FloodVerdict::Disconnect => {
+                super::log_at(LogLevel::Info, format!("Closing {:?} from {:?} for flooding", token, peer_ip));
+                self.close_with_notice(event_loop, token, "Disconnected for flooding");
+                return;
+            }
+        };
+
+        if let Some(reply) = reply {
+            self.get_connection(token).send_message(Rc::new(reply.into_bytes()));
+            return;
+        }
+
+        // Clients answer a PING on their own, so that doesn't mean the user is back
+        match command {
+            Some(ChatCommand::Pong) => {},
+            _ => self.app.mark_active(token)
+        }
+
         if is_command(&message) {
             self.handle_command_message(event_loop, token, &message);
             return;
         }
 
-        if let Some(username) = self.app.get_username(token) {
-            self.handle_message_from_authorized_user(event_loop, token, username, message);
+        if self.app.get_username(token).is_some() {
+            self.handle_message_from_authorized_user(event_loop, token, message);
             return;
         }
 
-        self.handle_message_from_unauthorized_user(token, message);
+        self.handle_message_from_unauthorized_user(event_loop, token, message);
     }
 
-    fn handle_message_from_unauthorized_user(&mut self, token: Token, message: String) {
+    fn handle_message_from_unauthorized_user(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, message: String) {
         // We could validate that this message has no whitepspace, but for now just take the first piece of the message
         // split by whitespace and use that as the clients username.
-        match message.split(char::is_whitespace).nth(0) {
+        match message.split_whitespace().next() {
             Some(name) => {
                 match self.app.register_user(token, name.to_string()) {
                     Ok(_) => {
+                        let reply = self.authorized_message(token);
                         let conn = self.get_connection(token);
-                        conn.send_message(Rc::new("Server: you have been successfully authorized\n".to_string().into_bytes()));
+                        conn.send_message(Rc::new(reply.into_bytes()));
+                        self.announce_to_rooms(event_loop, token, "joined");
                     },
                     Err(e) => {
                         super::log_something(format!("{}", e));
-                        self.connections[token].send_message(Rc::new("Server: That username is taken, please try another\n".to_string().into_bytes()))
+                        self.connections[token].send_message(Rc::new(format!("Server: {}\n", e).into_bytes()))
                     }
                 }
             },
@@ -126,30 +326,55 @@ impl ChatServer {
         }
     }
 
-    /// The user is sending a message to their current room. Create a shared pointer to the message and queue it up to be send to every
-    /// client in that same room the next time a write event for that client is recieved.
-    fn handle_message_from_authorized_user(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, username: String, message: String) {
+    /// The user is sending a message to one of their rooms, the one named by a `#room` prefix or else the one they have focused.
+    /// Create a shared pointer to the message and queue it up to be send to every client in that room the next time a write event
+    /// for that client is recieved.
+    fn handle_message_from_authorized_user(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, message: String) {
+        let (room_name, body) = match split_room_prefix(&message, &self.app.get_joined_rooms(token)) {
+            Some((room_name, body)) => (room_name, body),
+            None => (self.app.get_location(token).unwrap(), message)
+        };
+
+        if body.trim().is_empty() {
+            return;
+        }
+
+        if !self.app.can_speak(token, &room_name) {
+            self.get_connection(token).send_message(Rc::new(format!("Server: Room {} is read only, your message was discarded\n", room_name).into_bytes()));
+            return;
+        }
+
+        if self.app.is_muted(token, &room_name) {
+            self.get_connection(token).send_message(Rc::new(format!("Server: You are muted in room {}, your message was discarded\n", room_name).into_bytes()));
+            return;
+        }
+
+        let entry = match self.app.record_message(token, &room_name, &body) {
+            Some(entry) => entry,
+            None => {
+                return;
+            }
+        };
+        let mes_rc = Rc::new(format_history_entry(&self.config.timestamp_format, &self.sanitizer, &room_name, &entry));
+
+        // Hold on to the message for anyone in the room who dropped and might /resume
+        self.app.record_missed_message(&room_name, mes_rc.clone());
+
+        let tokens = self.app.get_room_tokens(&room_name);
+        self.send_to_all(event_loop, tokens, mes_rc);
+    }
+
+    /// Queue a message up for every connection in `tokens`, resetting any that can no longer be written to.
+    fn send_to_all(&mut self, event_loop: &mut EventLoop<ChatServer>, tokens: Vec<Token>, message: Rc<Vec<u8>>) {
         let mut bad_conn_tokens: Vec<Token> = Vec::new();
-        let timestamp = time::strftime("%Y:%m:%d %H:%M:%S", &time::now()).unwrap().into_bytes();
-        
-        // TODO: This could definitely be done more optimally but it works for now.
-        let mut mes_with_sender: Vec<u8> = timestamp;
-        mes_with_sender.extend(" - ".as_bytes());
-        mes_with_sender.extend(username.into_bytes());
-        mes_with_sender.extend(": ".as_bytes());
-        mes_with_sender.extend(message.as_bytes());
-        
-        let mes_rc = Rc::new(mes_with_sender);
-
-        // Enter a new scope so the borrow ends before we reset connections for bad tokens
-        {
-            let tokens = self.app.get_message_recipients(token);
-            for &recipient_token in tokens.iter() {
-                let conn = self.get_connection(recipient_token);
-                conn.send_message(mes_rc.clone());
-                if conn.reregister(event_loop).is_err() {
-                    bad_conn_tokens.push(recipient_token);
-                }
+
+        for recipient_token in tokens {
+            let conn = self.get_connection(recipient_token);
+            conn.send_message(message.clone());
+
+            // The connection is closed if its send queue was full under the disconnect policy
+            if conn.is_closed() || conn.reregister(event_loop).is_err() {
+                bad_conn_tokens.push(recipient_token);
             }
         }
 
@@ -158,63 +383,767 @@ impl ChatServer {
         }
     }
 
+    /// Send a server notice to everyone in the room except the given connection, prefixed by the room's name.
+    fn notify_room(&mut self, event_loop: &mut EventLoop<ChatServer>, room_name: &Roomname, except: Token, notice: String) {
+        let tokens = self.app.get_room_tokens(room_name).into_iter().filter(|&member| member != except).collect();
+        self.send_to_all(event_loop, tokens, Rc::new(format!("[{}] Server: {}\n", room_name, notice).into_bytes()));
+    }
+
+    /// Tell the rest of every room the user is in what they just did, e.g. "alice is back".
+    fn announce_to_rooms(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, action: &str) {
+        if let Some(username) = self.app.get_username(token) {
+            for room_name in self.app.get_joined_rooms(token) {
+                self.notify_room(event_loop, &room_name, token, format!("{} {}", username, action));
+            }
+        }
+    }
+
     /// Handle messages starting with a /. Currently, if the command doesn't match one of our existing commands we don't do anything
     fn handle_command_message(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, message: &String) {
-        match ChatCommand::new(message) {
-            Some(ChatCommand::ListRooms) => {
-                let mut list = String::new();
-                for room_name in self.app.get_room_list() {
-                    list.push_str(room_name.as_str());
-                    list.push('\n');
-                }
+        let command = match ChatCommand::new(message) {
+            Some(command) => command,
+            None => {
                 let conn = self.get_connection(token);
-                conn.send_message(Rc::new(list.clone().into_bytes()));
+                conn.send_message(Rc::new("Not a valid command\n".to_string().into_bytes()));
+                conn.reregister(event_loop);
+                return;
+            }
+        };
+
+        if let Err(e) = self.app.check_permission(token, command.permission()) {
+            let conn = self.get_connection(token);
+            conn.send_message(Rc::new(format!("Server: {}\n", e).into_bytes()));
+            conn.reregister(event_loop);
+            return;
+        }
+
+        match command {
+            ChatCommand::ListRooms(query) => {
+                self.app.expire_empty_rooms();
+                let reply = self.format_room_list(token, &query);
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::ListRoomMembers(room_name) => {
+                self.app.expire_empty_rooms();
+                let reply = match room_name.or(self.app.get_location(token)) {
+                    Some(room_name) => self.format_room_members(token, &room_name),
+                    None => "Server: Select a username or give a room name to list its members\n".to_string()
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
                 conn.reregister(event_loop);
             },
-            Some(ChatCommand::Quit) => {
+            ChatCommand::Pong => {
+                // Reading the reply already counted as the connection sending something, see `send_keepalive`
+            },
+            ChatCommand::Quit => {
+                // Leaving on purpose ends the session, there is nothing to resume
+                self.announce_to_rooms(event_loop, token, "left (quit)");
+                self.app.remove_user(token);
                 let conn = self.get_connection(token);
                 conn.quit();
             },
-            Some(ChatCommand::ChangeRoom(room_name)) => {
-                self.app.move_rooms(token, &room_name);
+            ChatCommand::Join(room_name, key) => {
+                // Room names are shown to everyone who joins or lists them, so they can't be cleaned up per message
+                if self.sanitizer.clean(&room_name) != room_name {
+                    let conn = self.get_connection(token);
+                    conn.send_message(Rc::new("Server: Room names can't contain control characters\n".to_string().into_bytes()));
+                    conn.reregister(event_loop);
+                    return;
+                }
+
+                if let Err(e) = self.enter_room(event_loop, token, &room_name, key.as_ref().map(|key| key.as_str())) {
+                    let conn = self.get_connection(token);
+                    conn.send_message(Rc::new(format!("Server: {}\n", e).into_bytes()));
+                    conn.reregister(event_loop);
+                }
+            },
+            ChatCommand::Part(room_name) => {
+                let room_name = room_name.unwrap_or(self.app.get_location(token).unwrap());
+                let reply = match self.app.part_room(token, &room_name) {
+                    Ok(focused) => {
+                        let username = self.app.get_username(token).unwrap();
+                        self.notify_room(event_loop, &room_name, token, format!("{} left", username));
+                        format!("Server: Left room {}, messages you send now go to room {}\n", room_name, focused)
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Focus(room_name) => {
+                let reply = match self.app.focus_room(token, &room_name) {
+                    Ok(_) => format!("Server: Messages you send now go to room {}\n", room_name),
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::History(count) => {
+                let reply = match self.app.get_location(token) {
+                    Some(room_name) => {
+                        let entries = self.app.get_history(&room_name, count.unwrap_or(self.config.limits.history_lines));
+                        let mut reply = format!("Server: last {} message(s) in room {}\n", entries.len(), room_name).into_bytes();
+                        for entry in entries.iter() {
+                            reply.extend(format_history_entry(&self.config.timestamp_format, &self.sanitizer, &room_name, entry));
+                        }
+                        reply
+                    },
+                    None => "Server: Select a username before asking for history\n".to_string().into_bytes()
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::PrivateMessage(recipient_name, text) => {
+                self.handle_private_message(event_loop, token, &recipient_name, &text);
+            },
+            ChatCommand::Topic(None) => {
+                let reply = match self.app.get_location(token) {
+                    Some(room_name) => {
+                        match self.app.get_topic(&room_name) {
+                            Some(topic) => format_topic(&self.config.timestamp_format, &self.sanitizer, &room_name, &topic),
+                            None => format!("Server: Room {} has no topic\n", room_name).into_bytes()
+                        }
+                    },
+                    None => "Server: Select a username before looking at topics\n".to_string().into_bytes()
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Topic(Some(text)) => {
+                let reply = match self.app.set_topic(token, text.clone()) {
+                    Ok(room_name) => {
+                        let username = self.app.get_username(token).unwrap();
+                        self.notify_room(event_loop, &room_name, token, format!("{} set the topic to: {}", username, self.sanitizer.clean(&text)));
+                        format!("Server: Topic for room {} set\n", room_name)
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::ChangeName(user_name) => {
+                let reply = match self.app.rename_user(token, user_name.clone()) {
+                    Ok(old_name) => {
+                        for room_name in self.app.get_joined_rooms(token) {
+                            self.notify_room(event_loop, &room_name, token, format!("{} is now known as {}", old_name, user_name));
+                        }
+                        format!("Server: You are now known as {}\n", user_name)
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Register(user_name, password) => {
+                match self.app.check_can_register(token, &user_name) {
+                    Ok(_) => {
+                        self.hasher.submit(token, user_name, HashJob::Create(password));
+                    },
+                    Err(e) => {
+                        let conn = self.get_connection(token);
+                        conn.send_message(Rc::new(format!("Server: {}\n", e).into_bytes()));
+                        conn.reregister(event_loop);
+                    }
+                }
+            },
+            ChatCommand::Login(user_name, password) => {
+                let peer_ip = self.connections[token].peer_ip();
+                let result = if self.app.get_username(token).is_some() {
+                    Err("You are already logged in".to_string())
+                } else if let Some(secs) = self.login_throttle.check(peer_ip) {
+                    Err(format!("Too many failed logins from your address, try again in {}", format_duration(time::Duration::seconds(secs as i64))))
+                } else {
+                    match self.app.get_account(&user_name) {
+                        Some((user_name, hash)) => {
+                            self.hasher.submit(token, user_name, HashJob::Verify(password, hash));
+                            Ok(())
+                        },
+                        None => {
+                            self.login_throttle.failed(peer_ip);
+                            Err("Invalid username or password".to_string())
+                        }
+                    }
+                };
+
+                if let Err(e) = result {
+                    let conn = self.get_connection(token);
+                    conn.send_message(Rc::new(format!("Server: {}\n", e).into_bytes()));
+                    conn.reregister(event_loop);
+                }
+            },
+            ChatCommand::Stats => {
+                let mut counts = self.overflow_totals;
+                for conn in self.connections.iter() {
+                    counts.add(conn.overflow_counts());
+                }
+
+                let reply = format!(
+                    "Server: {} connection(s), slow consumer policy {}\nServer: {} oldest message(s) dropped, {} newest message(s) dropped, {} connection(s) closed for not keeping up\n",
+                    self.connections.count(), self.config.slow_consumer_policy.name(),
+                    counts.dropped_oldest, counts.dropped_newest, counts.disconnects
+                );
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::SetRole(user_name, role) => {
+                let reply = match self.app.set_room_role(token, &user_name, role) {
+                    Ok(room_name) => {
+                        let giver = self.app.get_username(token).unwrap();
+                        self.notify_room(event_loop, &room_name, token, format!("{} made {} {} of room {}", giver, user_name, role.name(), room_name));
+                        format!("Server: {} is now {} of room {}\n", user_name, role.name(), room_name)
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Persist(persistent) => {
+                let room_name = self.app.get_location(token).unwrap();
+                let reply = match self.app.set_persistent(&room_name, persistent) {
+                    Ok(_) if persistent => format!("Server: Room {} will stay when everyone leaves\n", room_name),
+                    Ok(_) => format!("Server: Room {} will be removed once it has been empty for a while\n", room_name),
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Mode(None) => {
+                let room_name = self.app.get_location(token).unwrap();
+                let reply = match self.app.get_modes(&room_name) {
+                    Some(ref modes) if !modes.is_empty() => format!("Server: Room {} modes: {}\n", room_name, modes),
+                    _ => format!("Server: Room {} has no modes set\n", room_name)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Mode(Some(change)) => {
+                let reply = match self.app.set_mode(token, change.clone()) {
+                    Ok(room_name) => {
+                        let setter = self.app.get_username(token).unwrap();
+                        self.notify_room(event_loop, &room_name, token, format!("{} set mode {} on room {}", setter, change.name(), room_name));
+                        let modes = self.app.get_modes(&room_name).unwrap_or(String::new());
+                        format!("Server: Room {} modes are now: {}\n", room_name, if modes.is_empty() { "none" } else { &modes })
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Invite(user_name) => {
+                let reply = match self.app.invite(token, &user_name) {
+                    Ok((room_name, target)) => {
+                        let inviter = self.app.get_username(token).unwrap();
+                        let target_name = self.app.get_username(target).unwrap();
+                        let failed = {
+                            let conn = self.get_connection(target);
+                            conn.send_message(Rc::new(format!("Server: {} invited you to room {}, use /join {} to accept\n", inviter, room_name, room_name).into_bytes()));
+                            conn.is_closed() || conn.reregister(event_loop).is_err()
+                        };
+                        if failed {
+                            self.reset_connection(event_loop, target);
+                        }
+                        format!("Server: Invited {} to room {}\n", target_name, room_name)
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Kick(user_name, reason) => {
+                let reply = match self.app.kick(token, &user_name) {
+                    Ok((room_name, target)) => {
+                        let kicker = self.app.get_username(token).unwrap();
+                        let target_name = self.app.get_username(target).unwrap();
+                        let reason = reason.map(|reason| format!(": {}", self.sanitizer.clean(&reason))).unwrap_or(String::new());
+                        self.remove_from_room(event_loop, target, &room_name, &format!("You were kicked from room {} by {}{}", room_name, kicker, reason));
+                        self.notify_room(event_loop, &room_name, token, format!("{} kicked {}{}", kicker, target_name, reason));
+                        format!("Server: Kicked {} from room {}\n", target_name, room_name)
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                // Telling the room can reset the kicker's own connection if writing to it fails
+                if self.connections.contains(token) {
+                    let conn = self.get_connection(token);
+                    conn.send_message(Rc::new(reply.into_bytes()));
+                    conn.reregister(event_loop);
+                }
+            },
+            ChatCommand::Ban(mask, duration) => {
+                let reply = match self.app.ban(token, &mask, duration) {
+                    Ok((room_name, banned)) => {
+                        let banner = self.app.get_username(token).unwrap();
+                        let mask = self.sanitizer.clean(&mask);
+                        let how_long = duration.map(|secs| format!(" for {}", format_duration(time::Duration::seconds(secs as i64)))).unwrap_or(String::new());
+                        for target in banned {
+                            self.remove_from_room(event_loop, target, &room_name, &format!("You were banned from room {} by {}{}", room_name, banner, how_long));
+                        }
+                        self.notify_room(event_loop, &room_name, token, format!("{} banned {}{}", banner, mask, how_long));
+                        format!("Server: Banned {} from room {}{}\n", mask, room_name, how_long)
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                if self.connections.contains(token) {
+                    let conn = self.get_connection(token);
+                    conn.send_message(Rc::new(reply.into_bytes()));
+                    conn.reregister(event_loop);
+                }
+            },
+            ChatCommand::Unban(mask) => {
+                let reply = match self.app.unban(token, &mask) {
+                    Ok(room_name) => format!("Server: Lifted the ban on {} in room {}\n", self.sanitizer.clean(&mask), room_name),
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Mute(user_name, duration) => {
+                let reply = match self.app.mute(token, &user_name, duration) {
+                    Ok((room_name, target_name)) => {
+                        let muter = self.app.get_username(token).unwrap();
+                        let how_long = duration.map(|secs| format!(" for {}", format_duration(time::Duration::seconds(secs as i64)))).unwrap_or(String::new());
+                        self.notify_room(event_loop, &room_name, token, format!("{} muted {}{}", muter, target_name, how_long));
+                        format!("Server: Muted {} in room {}{}\n", target_name, room_name, how_long)
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
 
                 let conn = self.get_connection(token);
-                conn.send_message(Rc::new(format!("Moved to room {}\n", room_name).to_string().into_bytes()));
+                conn.send_message(Rc::new(reply.into_bytes()));
                 conn.reregister(event_loop);
+            },
+            ChatCommand::Unmute(user_name) => {
+                let reply = match self.app.unmute(token, &user_name) {
+                    Ok(room_name) => {
+                        let unmuter = self.app.get_username(token).unwrap();
+                        self.notify_room(event_loop, &room_name, token, format!("{} unmuted {}", unmuter, self.sanitizer.clean(&user_name)));
+                        format!("Server: Unmuted {} in room {}\n", self.sanitizer.clean(&user_name), room_name)
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Away(message) => {
+                let reply = match self.app.set_away(token, message.clone()) {
+                    Ok(_) => {
+                        match message {
+                            Some(message) => {
+                                let action = format!("is away: {}", self.sanitizer.clean(&message));
+                                self.announce_to_rooms(event_loop, token, &action);
+                            },
+                            None => {
+                                self.announce_to_rooms(event_loop, token, "is away");
+                            }
+                        }
+                        "Server: You are marked as away, send /back when you return\n".to_string()
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Back => {
+                let reply = match self.app.set_back(token) {
+                    Ok(_) => {
+                        self.announce_to_rooms(event_loop, token, "is back");
+                        "Server: You are no longer marked as away\n".to_string()
+                    },
+                    Err(e) => format!("Server: {}\n", e)
+                };
+
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+            },
+            ChatCommand::Resume(resume_token) => {
+                self.handle_resume(event_loop, token, &resume_token);
+            },
+        }
+
+
+        // Only log the command name, the arguments can contain passwords
+        super::log_something(format!("Command read {}", message.split_whitespace().next().unwrap_or("")));
+    }
+
+    /// Add the user to a room and focus it, catching them up on its topic and last few messages. The
+    /// room is told they joined, unless they were already in it.
+    fn enter_room(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, room_name: &Roomname, key: Option<&str>) -> Result<(), String> {
+        let already_joined = self.app.get_joined_rooms(token).contains(room_name);
+        let backlog = try!(self.app.join_room(token, room_name, key));
+
+        {
+            let conn = &mut self.connections[token];
+            if already_joined {
+                conn.send_message(Rc::new(format!("Switched to room {}\n", room_name).into_bytes()));
+            } else {
+                conn.send_message(Rc::new(format!("Joined room {}\n", room_name).into_bytes()));
+            }
+            if let Some(topic) = self.app.get_topic(room_name) {
+                conn.send_message(Rc::new(format_topic(&self.config.timestamp_format, &self.sanitizer, room_name, &topic)));
             }
+            for entry in backlog.iter() {
+                conn.send_message(Rc::new(format_history_entry(&self.config.timestamp_format, &self.sanitizer, room_name, entry)));
+            }
+            conn.reregister(event_loop);
+        }
+
+        if !already_joined {
+            let username = self.app.get_username(token).unwrap();
+            self.notify_room(event_loop, room_name, token, format!("{} joined", username));
+        }
+        Ok(())
+    }
+
+    /// Finish a /register once the hashing thread has hashed the password.
+    fn finish_register(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, user_name: Username, hash: Result<String, String>) {
+        let was_logged_in = self.app.get_username(token).is_some();
+        let reply = match hash.and_then(|hash| self.app.register_account(token, user_name, hash)) {
+            Ok(_) => format!("Server: Your account has been registered\n{}", self.authorized_message(token)),
+            Err(e) => format!("Server: {}\n", e)
+        };
+
+        let conn = self.get_connection(token);
+        conn.send_message(Rc::new(reply.into_bytes()));
+        conn.reregister(event_loop);
+
+        if !was_logged_in && self.app.get_username(token).is_some() {
+            self.announce_to_rooms(event_loop, token, "joined");
+        }
+    }
+
+    /// Finish a /login once the hashing thread has checked the password.
+    fn finish_login(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, user_name: Username, password_matches: bool) {
+        let result = if password_matches {
+            self.app.login(token, user_name)
+        } else {
+            self.login_throttle.failed(self.connections[token].peer_ip());
+            Err("Invalid username or password".to_string())
+        };
+
+        match result {
+            // The account's last connection dropped, logging in picks its session back up
+            Ok(Some(missed_messages)) => {
+                self.welcome_back(event_loop, token, missed_messages);
+            },
+            Ok(None) => {
+                let reply = self.authorized_message(token);
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(reply.into_bytes()));
+                conn.reregister(event_loop);
+
+                self.announce_to_rooms(event_loop, token, "joined");
+            },
+            Err(e) => {
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(format!("Server: {}\n", e).into_bytes()));
+                conn.reregister(event_loop);
+            }
+        }
+    }
+
+    /// Take a user who was kicked or banned out of a room, telling them why. Everyone has to be in a
+    /// room, so someone removed from the only room they are in is sent to the default room, or
+    /// disconnected if that is where they were removed from.
+    fn remove_from_room(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, room_name: &Roomname, notice: &str) {
+        // Telling the room about an earlier removal may have reset this connection
+        if !self.connections.contains(token) {
+            return;
+        }
+
+        let default_room = self.config.default_room.clone();
+        let only_room = self.app.get_joined_rooms(token).len() == 1;
+        let result = if only_room && *room_name == default_room {
+            Err(notice.to_string())
+        } else {
+            self.get_connection(token).send_message(Rc::new(format!("Server: {}\n", notice).into_bytes()));
+            if only_room {
+                self.enter_room(event_loop, token, &default_room, None)
+            } else {
+                Ok(())
+            }
+        };
+
+        // Either removed from the default room, or banned from it as well. There is no session to /resume
+        if let Err(e) = result {
+            self.app.remove_user(token);
+            self.close_with_notice(event_loop, token, &e);
+            return;
+        }
+
+        if let Err(e) = self.app.part_room(token, room_name) {
+            super::log_at(LogLevel::Warn, format!("Failed to remove {:?} from room {}, {}", token, room_name, e));
+        }
+    }
+
+    /// Restore a dropped session onto this connection and replay what was said in the room while the user was away.
+    fn handle_resume(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, resume_token: &String) {
+        match self.app.resume(token, resume_token) {
+            Ok(missed_messages) => {
+                self.welcome_back(event_loop, token, missed_messages);
+            },
+            Err(e) => {
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(format!("Server: {}\n", e).into_bytes()));
+                conn.reregister(event_loop);
+            }
+        }
+    }
+
+    /// Tell a user who picked their session back up where they are, along with their new resume token,
+    /// and replay what was said in their rooms while they were away.
+    fn welcome_back(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, missed_messages: Vec<Rc<Vec<u8>>>) {
+        let username = self.app.get_username(token).unwrap();
+        let location = self.app.get_location(token).unwrap();
+        let rooms = self.app.get_joined_rooms(token).join(", ");
+        let resume_notice = self.resume_notice(token);
+        let conn = self.get_connection(token);
+        conn.send_message(Rc::new(format!("Server: Welcome back {}, you are in room(s) {} and messages you send go to room {}\n{}", username, rooms, location, resume_notice).into_bytes()));
+        if !missed_messages.is_empty() {
+            conn.send_message(Rc::new(format!("Server: {} message(s) were sent while you were away:\n", missed_messages.len()).into_bytes()));
+            for message in missed_messages {
+                conn.send_message(message);
+            }
+        }
+        conn.reregister(event_loop);
+
+        self.announce_to_rooms(event_loop, token, "is back");
+    }
+
+    /// The reply sent once a connection has a username, including the token needed to /resume the session.
+    fn authorized_message(&self, token: Token) -> String {
+        format!("Server: you have been successfully authorized\n{}", self.resume_notice(token))
+    }
+
+    /// Tells the user the token they need to /resume their session, a new one every time they pick it back up.
+    fn resume_notice(&self, token: Token) -> String {
+        format!(
+            "Server: If you get disconnected, reconnect and send /resume {} to pick up where you left off\n",
+            self.app.get_resume_token(token).unwrap_or(String::new())
+        )
+    }
+
+    /// Build the reply for /rooms, one room per line with its member count, how long since it was last
+    /// active and its topic, a page at a time.
+    fn format_room_list(&self, token: Token, query: &RoomListQuery) -> String {
+        let mut rooms: Vec<&ChatRoom> = self.app.get_rooms(token).into_iter()
+            .filter(|room| query.pattern.as_ref().map(|pattern| glob::matches(pattern, &room.name)).unwrap_or(true))
+            .collect();
+
+        match query.sort {
+            RoomSort::Name => rooms.sort_by(|a, b| a.name.cmp(&b.name)),
+            RoomSort::Activity => rooms.sort_by(|a, b| {
+                match b.last_active.cmp(&a.last_active) {
+                    Ordering::Equal => a.name.cmp(&b.name),
+                    order => order
+                }
+            }),
+            RoomSort::Members => rooms.sort_by(|a, b| {
+                match b.members.len().cmp(&a.members.len()) {
+                    Ordering::Equal => a.name.cmp(&b.name),
+                    order => order
+                }
+            })
+        }
+
+        if rooms.is_empty() {
+            return "Server: No rooms match\n".to_string();
+        }
+
+        let per_page = self.config.limits.rooms_per_page;
+        let pages = (rooms.len() + per_page - 1) / per_page;
+        let page = ::std::cmp::min(query.page, pages);
+        let first = (page - 1) * per_page;
+        let last = ::std::cmp::min(first + per_page, rooms.len());
+
+        let mut list = format!("Server: rooms {}-{} of {}, page {} of {}\n", first + 1, last, rooms.len(), page, pages);
+        let now = time::get_time();
+        for room in rooms[first..last].iter() {
+            list.push_str(&room.name);
+            let modes = room.modes();
+            if !modes.is_empty() {
+                list.push_str(&format!(" [{}]", modes));
+            }
+            list.push_str(&format!(" ({} member(s), active {} ago)", room.members.len(), format_duration(now - room.last_active)));
+            if let Some(ref topic) = room.topic {
+                list.push_str(&format!(" - {}", self.sanitizer.clean(&topic.text)));
+            }
+            list.push('\n');
+        }
+
+        if page < pages {
+            list.push_str(&format!("Server: use -p {} for the next page\n", page + 1));
+        }
+        list
+    }
+
+    /// Build the reply for /who, one member per line along with their roles, whether they are away and how
+    /// long they have been idle.
+    fn format_room_members(&self, token: Token, room_name: &Roomname) -> String {
+        match self.app.get_room_members(token, room_name) {
+            Some(members) => {
+                let mut list = format!("Server: {} member(s) in room {}\n", members.len(), room_name);
+                for user in members {
+                    let mut details = Vec::new();
+                    if user.role == ServerRole::Operator {
+                        details.push("operator".to_string());
+                    }
+                    let room_role = self.app.get_room_role(user.id, room_name);
+                    if room_role != RoomRole::Member {
+                        details.push(room_role.name().to_string());
+                    }
+                    if let Some(away) = self.format_away(&user.current_presence(self.config.limits.auto_away_after)) {
+                        details.push(away);
+                    }
+                    details.push(format!("idle {}", format_duration(user.idle_time())));
+
+                    list.push_str(&format!("{} ({})\n", user.user_name, details.join(", ")));
+                }
+                list
+            },
+            None => {
+                format!("Server: There is no room named {}\n", room_name)
+            }
+        }
+    }
+
+    /// Describe an away user's presence, e.g. "away 5m 3s: at lunch", or None if they are here.
+    fn format_away(&self, presence: &Presence) -> Option<String> {
+        let now = time::get_time();
+        match *presence {
+            Presence::Here => None,
+            Presence::Away { message: Some(ref message), since } => Some(format!("away {}: {}", format_duration(now - since), self.sanitizer.clean(message))),
+            Presence::Away { message: None, since } => Some(format!("away {}", format_duration(now - since))),
+            Presence::Idle { since } => Some(format!("away {} (idle)", format_duration(now - since)))
+        }
+    }
+
+    /// Deliver a message to a single user, regardless of which rooms the sender and recipient are in.
+    /// The sender is sent a copy so the conversation shows up on both ends, and is told if the
+    /// recipient is away and may not see it for a while.
+    fn handle_private_message(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, recipient_name: &String, text: &String) {
+        let sender_name = match self.app.get_username(token) {
+            Some(username) => username,
             None => {
                 let conn = self.get_connection(token);
-                conn.send_message(Rc::new("Not a valid command\n".to_string().into_bytes()));
+                conn.send_message(Rc::new("Server: Select a username before sending private messages\n".to_string().into_bytes()));
+                conn.reregister(event_loop);
+                return;
+            }
+        };
+
+        // Show the recipient's name the way they picked it, not the way it was typed
+        let recipient = self.app.get_token(recipient_name)
+            .and_then(|recipient| self.app.get_username(recipient).map(|name| (recipient, name)));
+        let (recipient_token, recipient_name) = match recipient {
+            Some(recipient) => recipient,
+            None => {
+                let conn = self.get_connection(token);
+                conn.send_message(Rc::new(format!("Server: There is no user named {}\n", recipient_name).into_bytes()));
                 conn.reregister(event_loop);
+                return;
+            }
+        };
+
+        let sender_and_recipient = format!("{} -> {}", sender_name, recipient_name);
+        let mes_rc = Rc::new(format_chat_message(&self.config.timestamp_format, &self.sanitizer, time::get_time(), &sender_and_recipient, text));
+
+        let mut recipients = vec![recipient_token];
+        if recipient_token != token {
+            recipients.push(token);
+        }
+
+        for recipient in recipients {
+            let failed = {
+                let conn = self.get_connection(recipient);
+                conn.send_message(mes_rc.clone());
+                conn.is_closed() || conn.reregister(event_loop).is_err()
+            };
+
+            if failed {
+                self.reset_connection(event_loop, recipient);
             }
         }
 
-        
-        super::log_something(format!("Command read {}", message));
+        let away = self.app.get_presence(recipient_token).and_then(|presence| self.format_away(&presence));
+        if let (Some(away), true) = (away, recipient_token != token && self.connections.contains(token)) {
+            let conn = self.get_connection(token);
+            conn.send_message(Rc::new(format!("Server: {} is {}\n", recipient_name, away).into_bytes()));
+            conn.reregister(event_loop);
+        }
     }
 
-    /// If the server connection needs to be reset, then that means the application should be shut down.
+    /// If a listener connection needs to be reset, then that means the application should be shut down.
     fn reset_connection(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token) {
-        if SERVER_TOKEN == token {
+        if self.is_listener(token) {
             event_loop.shutdown();
-        } else {
+        } else if self.connections.contains(token) {
+            // Telling the room below can reset other connections in turn, so this may already have been removed
             self.connections[token].deregister(event_loop);
+            self.timers.cancel_all(event_loop, token);
+            self.hasher.cancel(token);
+            self.flood.forget(token);
+            self.overflow_totals.add(self.connections[token].overflow_counts());
             self.connections.remove(token);
-            self.app.remove_user(token);
+
+            let (username, rooms) = (self.app.get_username(token), self.app.get_joined_rooms(token));
+            self.app.suspend_user(token);
+
+            if let Some(username) = username {
+                for room_name in rooms {
+                    self.notify_room(event_loop, &room_name, token, format!("{} left (connection lost)", username));
+                }
+            }
+            self.schedule_room_expiry(event_loop);
         }
     }
 
     /// Reregister a connection with the event loop
     fn reregister(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token) {
-        if token == SERVER_TOKEN {
+        if self.is_listener(token) {
             event_loop.reregister(
-                &self.server,
-                SERVER_TOKEN,
+                &self.listeners[token.0 - SERVER_TOKEN.0],
+                token,
                 EventSet::readable(),
                 PollOpt::edge() | PollOpt::oneshot()
             ).unwrap_or_else(|e| {
-                super::log_something(format!("Failed to reregister server {:?}, {:?}", SERVER_TOKEN, e));
-                self.reset_connection(event_loop, SERVER_TOKEN);
+                super::log_at(LogLevel::Error, format!("Failed to reregister server {:?}, {:?}", token, e));
+                self.reset_connection(event_loop, token);
             });
         } else {
             // Todo, figure out the behavior when we we fail to reregister a client connection
@@ -226,12 +1155,36 @@ impl ChatServer {
         &mut self.connections[token]
     }
 
-    /// Function that is called when the chat server recieves a call to ready with its own token and a readable EventSet
+    /// Checks a new connection against the access list and the connection limits, returning why it
+    /// is refused if it is.
+    fn admission_refusal(&self, peer_ip: Option<IpAddr>) -> Option<&'static str> {
+        if let Some(ip) = peer_ip {
+            if !self.config.access.permits(ip) {
+                return Some("Connections from your address are not allowed");
+            }
+        }
+
+        if self.connections.count() >= self.config.limits.max_connections {
+            return Some("The server is full, try again later");
+        }
+
+        let max_per_ip = self.config.limits.max_connections_per_ip;
+        if let (Some(ip), true) = (peer_ip, max_per_ip > 0) {
+            let from_ip = self.connections.iter().filter(|conn| conn.peer_ip() == Some(ip)).count();
+            if from_ip >= max_per_ip {
+                return Some("Too many connections from your address, try again later");
+            }
+        }
+
+        None
+    }
+
+    /// Function that is called when the chat server recieves a call to ready with a listener's token and a readable EventSet
     /// Accept a new connection
-    fn accept(&mut self, event_loop: &mut EventLoop<ChatServer>) -> Result<(), String> {
+    fn accept(&mut self, event_loop: &mut EventLoop<ChatServer>, listener_token: Token) -> Result<(), String> {
 
         // Log an error if there is no socket
-        let sock = match self.server.accept() {
+        let sock = match self.listeners[listener_token.0 - SERVER_TOKEN.0].accept() {
             Ok(Some(socket)) => { socket },
             Ok(None) => {
                 return Err("Failed to accept new socket".to_string());
@@ -241,8 +1194,19 @@ impl ChatServer {
             }
         };
 
+        let peer_ip = sock.peer_addr().ok().map(|addr| addr.ip());
+        if let Some(reason) = self.admission_refusal(peer_ip) {
+            super::log_at(LogLevel::Info, format!("Refused connection from {:?}, {}", peer_ip, reason));
+
+            // Best effort, the socket is dropped straight after whether or not the client gets this
+            let mut sock = sock;
+            sock.try_write(format!("Server: {}\n", reason).as_bytes());
+            return Ok(());
+        }
+
         // If there was a socket, then register a new connection with it.
-        match self.connections.insert_with(|token| {ChatConnection::new(sock, token)}) {
+        let config = &self.config;
+        match self.connections.insert_with(|token| {ChatConnection::new(sock, token, config)}) {
             // If we successfully insert, then register our connection.
             Some(token) => {
 
@@ -254,13 +1218,20 @@ impl ChatServer {
                     }
                 }
 
-                match self.app.get_username(token) {
-                    Some(username) => {
-                        self.get_connection(token).send_message(Rc::new(format!("Server: Welcome back {}:\n", username).into()));
-                    },
-                    None => {
-                        self.get_connection(token).send_message(Rc::new("Server: Select a username:\n".into()));
-                    }
+                self.app.set_address(token, peer_ip);
+
+                // Tokens are handed out fresh for every socket, returning users pick their session back up with /resume
+                self.get_connection(token).send_message(Rc::new("Server: Select a username:\n".into()));
+
+                let limits = self.config.limits;
+                if limits.login_timeout > 0 {
+                    self.timers.schedule(event_loop, token, TimerKind::Login, limits.login_timeout);
+                }
+                if limits.idle_timeout > 0 {
+                    self.timers.schedule(event_loop, token, TimerKind::Idle, limits.idle_timeout);
+                }
+                if limits.keepalive_interval > 0 {
+                    self.timers.schedule(event_loop, token, TimerKind::Keepalive, limits.keepalive_interval);
                 }
             },
             None => {
@@ -273,9 +1244,9 @@ impl ChatServer {
 }
 
 impl mio::Handler for ChatServer {
-    type Timeout = (); // TODO
-    type Message = (); // Since the chat server is only single threaded, no need to worry about this.
-    // If it was multitreaded, all instances of Rc would need to be changed to Arc instead.
+    type Timeout = TimerEvent; // Scheduled through `Timers`, which says which connection and what to do
+    type Message = HashResult; // Sent back by the password hashing thread, the only other thread.
+    // Nothing else is shared with it, so the rest of the server can keep using Rc rather than Arc.
 
     // Called by the EventLoop whenever a socket is ready to be acted on.
     // Is passed the token for that socket and the current EventSet that socket is ready for.
@@ -301,13 +1272,127 @@ impl mio::Handler for ChatServer {
 
         if events.is_readable() {
             super::log_something(format!("Read event for {:?}", token));
-            if SERVER_TOKEN == token {
-                self.accept(event_loop);
-                self.reregister(event_loop, SERVER_TOKEN);
+            if self.is_listener(token) {
+                if let Err(e) = self.accept(event_loop, token) {
+                    super::log_at(LogLevel::Warn, e);
+                }
+                self.reregister(event_loop, token);
             } else {
 
                 self.read(event_loop, token);
             }
         }
     }
-}
\ No newline at end of file
+
+    // Called by the EventLoop when the password hashing thread has finished a job.
+    // Results for connections that have been reset since are ignored, see `PasswordHasher::finished`.
+    fn notify(&mut self, event_loop: &mut EventLoop<ChatServer>, result: HashResult) {
+        let user_name = match self.hasher.finished(&result) {
+            Some(user_name) => user_name,
+            None => {
+                return;
+            }
+        };
+
+        let token = result.token;
+        match result.outcome {
+            HashOutcome::Created(hash) => self.finish_register(event_loop, token, user_name, hash),
+            HashOutcome::Verified(password_matches) => self.finish_login(event_loop, token, user_name, password_matches)
+        }
+
+        // Catch up on whatever the client sent while it was waiting
+        self.handle_lines(event_loop, token);
+    }
+
+    // Called by the EventLoop when a timer scheduled through `Timers` fires.
+    // Timers are cancelled when a connection is reset, so the token can't belong to a newer connection.
+    fn timeout(&mut self, event_loop: &mut EventLoop<ChatServer>, event: TimerEvent) {
+        if !self.timers.fired(event) {
+            return;
+        }
+
+        if event.kind == TimerKind::EmptyRooms {
+            self.app.expire_empty_rooms();
+            self.schedule_room_expiry(event_loop);
+            return;
+        }
+
+        if !self.connections.contains(event.token) {
+            return;
+        }
+
+        let token = event.token;
+        match event.kind {
+            TimerKind::PartialLine => {
+                if self.connections[token].has_partial_line() {
+                    super::log_at(LogLevel::Info, format!("Closing {:?}, it didn't finish a line within {} seconds", token, self.config.limits.partial_line_timeout));
+                    self.close_with_notice(event_loop, token, "Timed out waiting for the end of your message");
+                }
+            },
+            TimerKind::Idle => {
+                self.check_idle(event_loop, token);
+            },
+            TimerKind::Keepalive => {
+                self.send_keepalive(event_loop, token);
+            },
+            TimerKind::Login => {
+                if self.app.get_username(token).is_none() {
+                    super::log_at(LogLevel::Info, format!("Closing {:?}, it didn't pick a username within {} seconds", token, self.config.limits.login_timeout));
+                    self.close_with_notice(event_loop, token, "Timed out waiting for a username");
+                }
+            },
+            TimerKind::EmptyRooms => {}
+        }
+    }
+}
+
+/// Prefix a message with the time it was sent and the name of whoever sent it.
+/// The timestamp format is checked when the config is loaded, so formatting can't fail here.
+/// The message is sanitized here rather than when it arrives, so history is stored as it was sent.
+fn format_chat_message(timestamp_format: &str, sanitizer: &Sanitizer, sent_at: Timespec, sender: &str, message: &str) -> Vec<u8> {
+    let timestamp = time::strftime(timestamp_format, &time::at(sent_at)).unwrap();
+    format!("{} - {}: {}\n", timestamp, sender, sanitizer.clean(message)).into_bytes()
+}
+
+/// A message sent to a room, prefixed by the room's name since users can be in several at once.
+fn format_history_entry(timestamp_format: &str, sanitizer: &Sanitizer, room_name: &Roomname, entry: &HistoryEntry) -> Vec<u8> {
+    let mut line = format!("[{}] ", room_name).into_bytes();
+    line.extend(format_chat_message(timestamp_format, sanitizer, entry.timestamp, &entry.sender, &entry.body));
+    line
+}
+
+/// Split a `#room text` line into the room's name and the text, or None if it doesn't start with one
+/// of `joined_rooms`. Lines like "#1 fan" or "#rust is great" that merely start with a '#' are
+/// ordinary messages.
+fn split_room_prefix(message: &str, joined_rooms: &[Roomname]) -> Option<(Roomname, String)> {
+    if !message.starts_with('#') {
+        return None;
+    }
+
+    let rest = &message[1..];
+    let name_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let room_name = &rest[..name_end];
+    if !joined_rooms.iter().any(|joined| joined == room_name) {
+        return None;
+    }
+    Some((room_name.to_string(), rest[name_end..].trim_left().to_string()))
+}
+
+fn format_topic(timestamp_format: &str, sanitizer: &Sanitizer, room_name: &Roomname, topic: &Topic) -> Vec<u8> {
+    let set_at = time::strftime(timestamp_format, &time::at(topic.set_at)).unwrap();
+    format!("Server: Topic for room {}: {} (set by {} at {})\n", room_name, sanitizer.clean(&topic.text), topic.set_by, set_at).into_bytes()
+}
+
+/// Format a duration as a short human readable string, e.g. "1h 5m 12s".
+fn format_duration(duration: time::Duration) -> String {
+    let total = duration.num_seconds();
+    let (hours, minutes, seconds) = (total / 3600, (total % 3600) / 60, total % 60);
+
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+use mio::{EventLoop, Timeout, Token};
+
+use super::config::LogLevel;
+use super::server::ChatServer;
+
+/// The kinds of delayed work that can be scheduled for a connection, or for the whole server.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum TimerKind {
+    /// The connection has part of a line buffered and must finish it in time, see limits.partial_line_timeout
+    PartialLine,
+
+    /// Check whether the connection has gone quiet for too long, see limits.idle_timeout
+    Idle,
+
+    /// Send a PING to a connection that hasn't sent anything for a while, see limits.keepalive_interval
+    Keepalive,
+
+    /// The connection must pick a username or log in before this fires, see limits.login_timeout
+    Login,
+
+    /// Remove the rooms that have been empty for limits.empty_room_grace_period. It isn't for any
+    /// one connection, so it is scheduled for `SERVER_TOKEN`
+    EmptyRooms
+}
+
+/// What the event loop hands back to `ChatServer::timeout` when a timer fires.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct TimerEvent {
+    pub token: Token,
+    pub kind: TimerKind
+}
+
+/// Every timer currently scheduled on the event loop, at most one of each kind per connection.
+///
+/// The handles are kept so timers can be cancelled when a connection is reset. Slab tokens are
+/// reused, so a timer left running could otherwise fire for whichever connection gets the token next.
+pub struct Timers {
+    scheduled: HashMap<TimerEvent, Timeout>
+}
+
+impl Timers {
+    pub fn new() -> Timers {
+        Timers {
+            scheduled: HashMap::new()
+        }
+    }
+
+    /// Schedule a timer to fire after `delay_secs`, replacing any of the same kind already scheduled for the connection.
+    pub fn schedule(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, kind: TimerKind, delay_secs: u64) {
+        self.cancel(event_loop, token, kind);
+
+        let event = TimerEvent { token: token, kind: kind };
+        match event_loop.timeout_ms(event, delay_secs * 1000) {
+            Ok(timeout) => {
+                self.scheduled.insert(event, timeout);
+            },
+            Err(e) => {
+                super::log_at(LogLevel::Error, format!("Failed to schedule {:?} timer for {:?}, {:?}", kind, token, e));
+            }
+        }
+    }
+
+    pub fn is_scheduled(&self, token: Token, kind: TimerKind) -> bool {
+        self.scheduled.contains_key(&TimerEvent { token: token, kind: kind })
+    }
+
+    pub fn cancel(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token, kind: TimerKind) {
+        if let Some(timeout) = self.scheduled.remove(&TimerEvent { token: token, kind: kind }) {
+            event_loop.clear_timeout(timeout);
+        }
+    }
+
+    /// Cancel every timer for a connection, called when it is reset.
+    pub fn cancel_all(&mut self, event_loop: &mut EventLoop<ChatServer>, token: Token) {
+        let events: Vec<TimerEvent> = self.scheduled.keys().filter(|event| event.token == token).cloned().collect();
+        for event in events {
+            self.cancel(event_loop, event.token, event.kind);
+        }
+    }
+
+    /// Forget a timer that just fired. Returns false if it had already been cancelled, in which case
+    /// it should be ignored.
+    pub fn fired(&mut self, event: TimerEvent) -> bool {
+        self.scheduled.remove(&event).is_some()
+    }
+}
@@ -1,10 +1,225 @@
+use std::collections::VecDeque;
+use std::rc::Rc;
+
 use mio::Token;
+use time::{self, Timespec};
+use super::access::IpRange;
+use super::config::ServerConfig;
+use super::roles::ServerRole;
 use super::room::Roomname;
+use super::sanitize::is_unsafe;
 
 pub type Username = String;
 
 pub struct ChatUser {
     pub id: Token,
     pub user_name: Username,
-    pub location: Roomname
+
+    /// Every room the user is in, in the order they joined them
+    pub rooms: Vec<Roomname>,
+
+    /// The room messages without a `#room` prefix go to, and that commands act on. Always one of `rooms`
+    pub location: Roomname,
+
+    pub role: ServerRole,
+
+    /// When this user last sent anything to the server, used to report how long they have been idle
+    pub last_active: Timespec,
+
+    /// Here, or away since they sent /away. Use `current_presence` to count being idle as away too
+    pub presence: Presence,
+
+    /// Secret handed to the user when they log in, lets them pick their session back up with /resume
+    /// if their connection drops
+    pub resume_token: String
+}
+
+impl ChatUser {
+    /// How long it has been since this user last sent anything to the server.
+    pub fn idle_time(&self) -> time::Duration {
+        time::get_time() - self.last_active
+    }
+
+    /// The user's presence, counting them as away once they have been idle for `auto_away_after`
+    /// seconds. 0 never counts them as away.
+    pub fn current_presence(&self, auto_away_after: u64) -> Presence {
+        if self.presence == Presence::Here && auto_away_after > 0 && self.idle_time().num_seconds() as u64 >= auto_away_after {
+            return Presence::Idle { since: self.last_active };
+        }
+        self.presence.clone()
+    }
+}
+
+/// Whether a user is around to see messages, shown in /who and to anyone who /msgs them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Presence {
+    Here,
+
+    /// Set with /away, until they send /back
+    Away { message: Option<String>, since: Timespec },
+
+    /// Hasn't sent anything for limits.auto_away_after, over as soon as they do
+    Idle { since: Timespec }
+}
+
+/// A user whose connection dropped without a /quit. Their name and rooms are held for a grace
+/// period so they can /resume, and messages sent to their rooms in the meantime are kept for them.
+pub struct SuspendedUser {
+    pub user: ChatUser,
+
+    /// When the connection dropped
+    pub since: Timespec,
+
+    /// Messages sent to the user's rooms since they were disconnected, oldest first
+    pub missed_messages: VecDeque<Rc<Vec<u8>>>
+}
+
+/// Rules for the usernames people can pick, checked whenever a new name is claimed. Names that were
+/// already registered to an account before the rules changed can still be logged in to.
+pub struct UsernamePolicy {
+    max_length: usize,
+
+    /// Letters and numbers outside of ASCII are allowed
+    allow_unicode: bool,
+
+    /// Lookup keys of the names nobody can use, see `username_key`
+    reserved: Vec<String>
+}
+
+impl UsernamePolicy {
+    pub fn new(config: &ServerConfig) -> UsernamePolicy {
+        UsernamePolicy {
+            max_length: config.limits.max_username_length,
+            allow_unicode: config.allow_unicode_names,
+            reserved: config.reserved_names.iter().map(|name| username_key(name)).collect()
+        }
+    }
+
+    pub fn validate(&self, user_name: &str) -> Result<(), String> {
+        let length = user_name.chars().count();
+        if length == 0 || length > self.max_length {
+            return Err(format!("Usernames must be between 1 and {} characters long", self.max_length));
+        }
+
+        if !user_name.chars().all(|c| (c.is_alphanumeric() && !is_unsafe(c)) || c == '_' || c == '-' || c == '.') {
+            return Err("Usernames can only contain letters, numbers, '_', '-' and '.'".into());
+        }
+
+        // The look-alike table in `skeleton_char` only covers the common confusables, so by default
+        // names are kept to ASCII where there are none it misses
+        if !self.allow_unicode && user_name.chars().any(|c| c as u32 > 0x7f) {
+            return Err("Usernames can only contain the letters A to Z, numbers, '_', '-' and '.'".into());
+        }
+
+        if !user_name.chars().next().map(|c| c.is_alphanumeric()).unwrap_or(false) {
+            return Err("Usernames must start with a letter or a number".into());
+        }
+
+        // /ban takes both names and addresses, so a name like 10.0.0.1 could never be banned by name
+        if IpRange::parse(user_name).is_ok() {
+            return Err("Usernames can't be IP addresses, please try another".into());
+        }
+
+        if self.reserved.contains(&username_key(user_name)) {
+            return Err("That username is reserved, please try another".into());
+        }
+
+        Ok(())
+    }
+}
+
+/// The key usernames are compared by, so names that only differ in case or that look the same on
+/// screen can't both be in use. "Alice", "ALICE" and "аlice" with a Cyrillic "а" all have the key "alice".
+///
+/// This covers the look-alikes that come up in practice, not every confusable in Unicode. Case folding
+/// wins over look-alikes, so "I" is treated as "i" even though it looks more like "l".
+pub fn username_key(user_name: &str) -> String {
+    let mut key = String::new();
+    for c in user_name.chars().flat_map(|c| c.to_lowercase()) {
+        key.push(skeleton_char(c));
+    }
+
+    // Letter pairs that read as a single letter
+    key.replace("rn", "m").replace("vv", "w")
+}
+
+/// Map a lowercase character to the ASCII letter it looks like, or leave it be.
+fn skeleton_char(c: char) -> char {
+    match c {
+        // Fullwidth ASCII, from the forms CJK input methods produce
+        '\u{ff01}'...'\u{ff5e}' => {
+            ::std::char::from_u32(c as u32 - 0xfee0).map(skeleton_char).unwrap_or(c)
+        },
+        '0' => 'o',
+        '1' => 'l',
+
+        // Latin letters without a dot or in other scripts' shapes, and Roman numerals
+        'ı' | 'ɩ' | 'ɪ' | 'ⅰ' => 'i', 'ȷ' => 'j', 'ɑ' => 'a', 'ɡ' => 'g', 'ʟ' | 'ⅼ' => 'l',
+        'ʋ' => 'v', 'ⅽ' => 'c', 'ⅾ' => 'd', 'ⅿ' => 'm', 'ⅴ' => 'v', 'ⅹ' => 'x',
+
+        // Cyrillic, lowercased from uppercase forms like В and Н as well
+        'а' => 'a', 'в' => 'b', 'е' | 'ё' => 'e', 'һ' | 'н' => 'h', 'і' | 'ї' => 'i',
+        'ј' => 'j', 'к' => 'k', 'ӏ' => 'l', 'м' => 'm', 'о' => 'o', 'р' => 'p', 'ԛ' => 'q',
+        'ѕ' => 's', 'т' => 't', 'с' => 'c', 'у' => 'y', 'ԁ' => 'd', 'ԝ' => 'w', 'х' => 'x',
+
+        // Greek
+        'α' => 'a', 'β' => 'b', 'ε' => 'e', 'η' => 'h', 'ι' => 'i', 'κ' => 'k', 'μ' => 'm',
+        'ν' => 'v', 'ο' => 'o', 'ρ' => 'p', 'τ' => 't', 'υ' => 'y', 'χ' => 'x', 'ζ' => 'z',
+        'ϲ' => 'c', 'ϳ' => 'j',
+
+        // Armenian
+        'օ' => 'o', 'ս' => 'u', 'ո' => 'n', 'հ' => 'h', 'ց' => 'g', 'ք' => 'p', 'զ' => 'q',
+
+        _ => c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::config::ServerConfig;
+    use super::{username_key, UsernamePolicy};
+
+    #[test]
+    fn keys_ignore_case() {
+        assert_eq!(username_key("Alice"), "alice");
+        assert_eq!(username_key("ALICE"), username_key("alice"));
+    }
+
+    #[test]
+    fn look_alikes_share_a_key() {
+        // Cyrillic а and е, Greek ο, Armenian օ, dotless ı and the Roman numeral ⅼ
+        assert_eq!(username_key("аlicе"), "alice");
+        assert_eq!(username_key("bοb"), "bob");
+        assert_eq!(username_key("օscar"), "oscar");
+        assert_eq!(username_key("alıce"), "alice");
+        assert_eq!(username_key("aⅼice"), "alice");
+    }
+
+    #[test]
+    fn digits_and_letter_pairs_read_as_letters() {
+        assert_eq!(username_key("b0b"), username_key("bob"));
+        assert_eq!(username_key("a1ice"), username_key("alice"));
+        assert_eq!(username_key("rnary"), username_key("mary"));
+        assert_eq!(username_key("vvill"), username_key("will"));
+    }
+
+    #[test]
+    fn fullwidth_forms_are_folded() {
+        assert_eq!(username_key("ａｌｉｃｅ"), "alice");
+        assert_eq!(username_key("ＡＬＩＣＥ"), "alice");
+    }
+
+    #[test]
+    fn different_names_keep_different_keys() {
+        assert!(username_key("alice") != username_key("alicia"));
+        assert!(username_key("bob") != username_key("rob"));
+    }
+
+    #[test]
+    fn addresses_are_not_usernames() {
+        let policy = UsernamePolicy::new(&ServerConfig::default());
+        assert!(policy.validate("10.0.0.1").is_err());
+        assert!(policy.validate("10.0.0").is_ok());
+        assert!(policy.validate("alice.1").is_ok());
+    }
 }
@@ -5,9 +5,54 @@
 extern crate mio;
 extern crate bytes;
 extern crate time;
+extern crate crypto;
+extern crate rand;
+extern crate toml;
+extern crate getopts;
 
 mod chat_server;
 
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+use chat_server::config::{ConfigError, ServerConfig};
+
 pub fn main() {
-    chat_server::run_server("0.0.0.0:6567".parse().unwrap());
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().collect();
+
+    let config = match ServerConfig::from_args(&args) {
+        Ok(config) => config,
+        Err(ConfigError::Help(usage)) => {
+            println!("{}", usage);
+            return;
+        },
+        Err(ConfigError::HashPassword) => {
+            print_password_hash();
+            return;
+        },
+        Err(ConfigError::Invalid(e)) => {
+            writeln!(io::stderr(), "{}", e);
+            process::exit(1);
+        }
+    };
+
+    chat_server::run_server(config);
+}
+
+/// Read a password from standard input and print the hash to put in `operator_passwords`.
+fn print_password_hash() {
+    let mut password = String::new();
+    if let Err(e) = io::stdin().read_line(&mut password) {
+        writeln!(io::stderr(), "Failed to read a password: {}", e);
+        process::exit(1);
+    }
+
+    match chat_server::hash_password(password.trim_right_matches(|c| c == '\r' || c == '\n')) {
+        Ok(hash) => println!("{}", hash),
+        Err(e) => {
+            writeln!(io::stderr(), "{}", e);
+            process::exit(1);
+        }
+    }
+}